@@ -0,0 +1,60 @@
+#![feature(test)]
+extern crate test;
+
+use rayon::prelude::*;
+use rustmp::{as_scoped_job, par_for, RmpIterator, ThreadPool};
+
+// Each iteration starts one parallel region with a single empty iteration per thread, so these
+// measure the fixed cost of launching and joining a loop.
+
+#[bench]
+/// Launch overhead of par_for!.
+fn launch_rmp_par_for(b: &mut test::Bencher) {
+    let n = rustmp::get_max_threads();
+    b.iter(|| {
+        par_for! {
+            for i in 0..n, {
+                test::black_box(i);
+            }
+        }
+    });
+}
+
+#[bench]
+/// Launch overhead of a team's exec_scoped(), without par_for!'s iterator splitting.
+fn launch_rmp_exec_scoped(b: &mut test::Bencher) {
+    let team = ThreadPool::global().team();
+    b.iter(|| {
+        team.exec_scoped(
+            (0..team.num_threads)
+                .map(|i| {
+                    as_scoped_job(move || {
+                        test::black_box(i);
+                    })
+                })
+                .collect(),
+        )
+    });
+}
+
+#[bench]
+/// Launch overhead of RmpIterator::rmp_for_each().
+fn launch_rmp_iter(b: &mut test::Bencher) {
+    let n = rustmp::get_max_threads();
+    b.iter(|| {
+        (0..n).rmp_for_each(|i| {
+            test::black_box(i);
+        })
+    });
+}
+
+#[bench]
+/// Launch overhead of rayon::par_iter.
+fn launch_rayon(b: &mut test::Bencher) {
+    let n = rayon::current_num_threads();
+    b.iter(|| {
+        (0..n).into_par_iter().for_each(|i| {
+            test::black_box(i);
+        })
+    });
+}
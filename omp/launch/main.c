@@ -0,0 +1,57 @@
+#include <omp.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <time.h>
+
+void
+print_time_diff (struct timespec *start, struct timespec *end, long nregions)
+{
+  time_t sec;
+  long nsec;
+  sec = end->tv_sec - start->tv_sec;
+  nsec = end->tv_nsec - start->tv_nsec;
+
+  if (nsec < 0)
+    {
+      sec--;
+      nsec += 1000000000;
+    }
+
+  printf ("Elapsed time: %ld.%09lds\n", sec, nsec);
+  printf ("Per region: %.3fus\n", (sec * 1e9 + nsec) / 1e3 / nregions);
+}
+
+void
+warmup() {
+    size_t i;
+    #pragma omp parallel for
+    for (i = 0; i < 1; i++) {}
+}
+
+int
+main (int argc, char *argv[])
+{
+    struct timespec start, end;
+    long r, nregions;
+    int i, nthreads;
+    if (argc != 2) {
+        printf ("Usage: %s <nregions>\n", argv[0]);
+        exit(-1);
+    }
+    nregions = atol (argv[1]);
+    if (nregions < 1) {
+        printf ("Usage: %s <nregions>\n", argv[0]);
+        exit(-1);
+    }
+    nthreads = omp_get_max_threads ();
+    warmup();
+    clock_gettime (CLOCK_MONOTONIC, &start);
+    for (r = 0; r < nregions; r++) {
+        #pragma omp parallel for
+        for (i = 0; i < nthreads; i++) {
+            __asm__ volatile ("" : : "r" (i) : "memory");
+        }
+    }
+    clock_gettime (CLOCK_MONOTONIC, &end);
+    print_time_diff (&start, &end, nregions);
+}
@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::visit::{self, Visit};
+use syn::{Arm, Block, Expr, ExprCall, ExprClosure, ExprForLoop, ExprLet, ExprPath, Ident};
+use syn::{Item, Lit, Local, Macro, Pat, PatIdent, Token};
+
+/// Finds the variables from the enclosing scope that a loop body refers to.
+///
+/// This is a syntactic approximation: a single segment path that is not bound by a pattern
+/// inside the body is assumed to be an outer variable, unless it starts with an uppercase
+/// letter (constants, statics, unit structs and variants) or is called like a function.
+/// Macro arguments are checked when they parse as expressions, including variables captured
+/// by format strings.
+struct Captures {
+    scopes: Vec<HashSet<String>>,
+    seen: HashSet<String>,
+    found: Vec<Ident>,
+}
+
+struct Bindings<'a>(&'a mut HashSet<String>);
+
+impl<'a, 'ast> Visit<'ast> for Bindings<'a> {
+    fn visit_pat_ident(&mut self, pat: &'ast PatIdent) {
+        self.0.insert(pat.ident.to_string());
+        visit::visit_pat_ident(self, pat);
+    }
+}
+
+impl Captures {
+    fn bind(&mut self, pat: &Pat) {
+        Bindings(self.scopes.last_mut().unwrap()).visit_pat(pat);
+    }
+
+    fn reference(&mut self, ident: &Ident) {
+        let name = ident.to_string();
+        if name == "self"
+            || name.starts_with(char::is_uppercase)
+            || self.scopes.iter().any(|scope| scope.contains(&name))
+        {
+            return;
+        }
+        if self.seen.insert(name) {
+            self.found.push(ident.clone());
+        }
+    }
+
+    fn format_string(&mut self, lit: &syn::LitStr) {
+        let value = lit.value();
+        let mut rest = value.as_str();
+        while let Some(start) = rest.find('{') {
+            rest = &rest[start + 1..];
+            if rest.starts_with('{') {
+                rest = &rest[1..];
+                continue;
+            }
+            let end = rest.find(['}', ':']).unwrap_or(rest.len());
+            if let Ok(ident) = syn::parse_str::<Ident>(&rest[..end]) {
+                self.reference(&Ident::new(&ident.to_string(), lit.span()));
+            }
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Captures {
+    fn visit_block(&mut self, block: &'ast Block) {
+        self.scopes.push(HashSet::new());
+        visit::visit_block(self, block);
+        self.scopes.pop();
+    }
+
+    fn visit_local(&mut self, local: &'ast Local) {
+        if let Some(init) = &local.init {
+            self.visit_expr(&init.expr);
+            if let Some((_, diverge)) = &init.diverge {
+                self.visit_expr(diverge);
+            }
+        }
+        self.bind(&local.pat);
+    }
+
+    fn visit_expr_closure(&mut self, closure: &'ast ExprClosure) {
+        self.scopes.push(HashSet::new());
+        for input in &closure.inputs {
+            self.bind(input);
+        }
+        self.visit_expr(&closure.body);
+        self.scopes.pop();
+    }
+
+    fn visit_expr_for_loop(&mut self, lp: &'ast ExprForLoop) {
+        self.visit_expr(&lp.expr);
+        self.scopes.push(HashSet::new());
+        self.bind(&lp.pat);
+        self.visit_block(&lp.body);
+        self.scopes.pop();
+    }
+
+    fn visit_arm(&mut self, arm: &'ast Arm) {
+        self.scopes.push(HashSet::new());
+        self.bind(&arm.pat);
+        if let Some((_, guard)) = &arm.guard {
+            self.visit_expr(guard);
+        }
+        self.visit_expr(&arm.body);
+        self.scopes.pop();
+    }
+
+    fn visit_expr_let(&mut self, expr: &'ast ExprLet) {
+        self.visit_expr(&expr.expr);
+        self.bind(&expr.pat);
+    }
+
+    fn visit_expr_call(&mut self, call: &'ast ExprCall) {
+        match &*call.func {
+            Expr::Path(path) if path.path.get_ident().is_some() => {}
+            func => self.visit_expr(func),
+        }
+        for arg in &call.args {
+            self.visit_expr(arg);
+        }
+    }
+
+    fn visit_expr_path(&mut self, expr: &'ast ExprPath) {
+        if expr.qself.is_none() {
+            if let Some(ident) = expr.path.get_ident() {
+                self.reference(ident);
+            }
+        }
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        let args = Punctuated::<Expr, Token![,]>::parse_terminated.parse2(mac.tokens.clone());
+        for arg in args.into_iter().flatten() {
+            match &arg {
+                Expr::Lit(lit) => {
+                    if let Lit::Str(s) = &lit.lit {
+                        self.format_string(s);
+                    }
+                }
+                _ => self.visit_expr(&arg),
+            }
+        }
+    }
+
+    fn visit_item(&mut self, _item: &'ast Item) {
+        // Nested items cannot refer to local variables
+    }
+}
+
+/// Returns the outer variables used by a loop with pattern `pat` and body `body`, in order of
+/// first use, excluding the names in `listed`.
+pub fn free_variables(pat: &Pat, body: &Block, listed: &[&Ident]) -> Vec<Ident> {
+    let mut captures = Captures {
+        scopes: vec![listed.iter().map(|v| v.to_string()).collect()],
+        seen: HashSet::new(),
+        found: Vec::new(),
+    };
+    captures.bind(pat);
+    captures.visit_block(body);
+    captures.found
+}
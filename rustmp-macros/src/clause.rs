@@ -0,0 +1,353 @@
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parenthesized, BinOp, Block, Error, Expr, Ident, Pat, Result, Token, Type};
+
+use crate::capture::free_variables;
+
+/// Reduction operator, either a binary operator or the name of a function `fn(T, &T) -> T`.
+#[derive(Clone)]
+pub enum ReductionOp {
+    Op(BinOp),
+    Func(Ident),
+}
+
+impl Parse for ReductionOp {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if input.peek(Ident) {
+            return Ok(ReductionOp::Func(input.parse()?));
+        }
+        let op: BinOp = input.parse()?;
+        match op {
+            BinOp::Add(_)
+            | BinOp::Mul(_)
+            | BinOp::BitAnd(_)
+            | BinOp::BitOr(_)
+            | BinOp::BitXor(_) => Ok(ReductionOp::Op(op)),
+            _ => Err(Error::new_spanned(
+                op,
+                "unsupported reduction operator, expected one of `+ * & | ^` or a function name",
+            )),
+        }
+    }
+}
+
+impl ToTokens for ReductionOp {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        match self {
+            ReductionOp::Op(op) => op.to_tokens(tokens),
+            ReductionOp::Func(f) => f.to_tokens(tokens),
+        }
+    }
+}
+
+/// Data sharing of variables that are used in the loop body but not listed in any clause.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DefaultSharing {
+    /// Borrowed from the enclosing scope, the same as without a default clause
+    Shared,
+    /// Rejected at compile time
+    None,
+    /// Cloned into each thread, as if listed in private
+    Private,
+}
+
+impl Parse for DefaultSharing {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let kind: Ident = input.parse()?;
+        match kind.to_string().as_str() {
+            "shared" => Ok(DefaultSharing::Shared),
+            "none" => Ok(DefaultSharing::None),
+            "private" => Ok(DefaultSharing::Private),
+            _ => Err(Error::new_spanned(
+                kind,
+                "expected one of `shared`, `none` or `private`",
+            )),
+        }
+    }
+}
+
+/// A single clause of a parallel for loop, as written by the user.
+pub enum Clause {
+    Blocksize(Ident, Expr),
+    Vars(Ident, Vec<Ident>),
+    Copyin(Vec<Ident>),
+    Reduction(ReductionOp, Vec<Ident>),
+    Fallible(Ident, Ident, Type),
+    Collect(Ident, Ident),
+    Pool(Ident, Expr),
+    Default(Ident, DefaultSharing),
+}
+
+const VAR_CLAUSES: &[&str] = &[
+    "shared",
+    "shared_mut",
+    "shared_unsafe",
+    "split_mut",
+    "private",
+];
+
+fn parse_vars(input: ParseStream) -> Result<Vec<Ident>> {
+    let vars = Punctuated::<Ident, Token![,]>::parse_terminated(input)?;
+    if vars.is_empty() {
+        return Err(input.error("expected at least one variable"));
+    }
+    Ok(vars.into_iter().collect())
+}
+
+impl Parse for Clause {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name: Ident = input.parse()?;
+        let content;
+        parenthesized!(content in input);
+        let clause = match name.to_string().as_str() {
+            "blocksize" => Clause::Blocksize(name, content.parse()?),
+            "reduction" => {
+                let op = content.parse()?;
+                content.parse::<Token![:]>()?;
+                Clause::Reduction(op, parse_vars(&content)?)
+            }
+            "fallible" => {
+                let res = content.parse()?;
+                content.parse::<Token![:]>()?;
+                Clause::Fallible(name, res, content.parse()?)
+            }
+            "collect" => Clause::Collect(name, content.parse()?),
+            "default" => Clause::Default(name, content.parse()?),
+            "copyin" => Clause::Copyin(parse_vars(&content)?),
+            "pool" => Clause::Pool(name, content.parse()?),
+            s if VAR_CLAUSES.contains(&s) => Clause::Vars(name, parse_vars(&content)?),
+            _ => {
+                return Err(Error::new_spanned(
+                    &name,
+                    format!(
+                        "unknown clause `{}`, expected one of blocksize, shared, shared_mut, \
+                         shared_unsafe, split_mut, private, reduction, copyin, fallible, \
+                         collect, pool, default",
+                        name
+                    ),
+                ))
+            }
+        };
+        if !content.is_empty() {
+            return Err(content.error("unexpected tokens in clause"));
+        }
+        Ok(clause)
+    }
+}
+
+/// All clauses of a loop, checked for conflicts and sorted by kind.
+#[derive(Default)]
+pub struct Clauses {
+    blocksize: Option<Expr>,
+    shared: Vec<Ident>,
+    shared_mut: Vec<Ident>,
+    shared_unsafe: Vec<Ident>,
+    split_mut: Vec<Ident>,
+    private: Vec<Ident>,
+    copyin: Vec<Ident>,
+    reduction: Vec<(Ident, ReductionOp)>,
+    fallible: Option<(Ident, Type)>,
+    collect: Option<Ident>,
+    pool: Option<Expr>,
+    default: Option<DefaultSharing>,
+}
+
+impl Clauses {
+    /// Variables listed in any data sharing clause, with the clause they were listed in.
+    fn vars(&self) -> impl Iterator<Item = (&Ident, &'static str)> {
+        let lists: [(&[Ident], &'static str); 5] = [
+            (&self.shared, "shared"),
+            (&self.shared_mut, "shared_mut"),
+            (&self.shared_unsafe, "shared_unsafe"),
+            (&self.split_mut, "split_mut"),
+            (&self.private, "private"),
+        ];
+        IntoIterator::into_iter(lists)
+            .flat_map(|(vars, clause)| vars.iter().map(move |v| (v, clause)))
+            .chain(self.reduction.iter().map(|(v, _)| (v, "reduction")))
+    }
+
+    fn check_var(&self, var: &Ident) -> Result<()> {
+        if let Some((_, prev)) = self.vars().find(|(v, _)| *v == var) {
+            return Err(Error::new_spanned(
+                var,
+                format!("variable `{}` is already listed in a {} clause", var, prev),
+            ));
+        }
+        if self.collect.as_ref() == Some(var) {
+            return Err(Error::new_spanned(
+                var,
+                format!(
+                    "variable `{}` is already the target of the collect clause",
+                    var
+                ),
+            ));
+        }
+        if self.fallible.as_ref().map(|(res, _)| res) == Some(var) {
+            return Err(Error::new_spanned(
+                var,
+                format!(
+                    "variable `{}` is already the target of the fallible clause",
+                    var
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, clause: Clause) -> Result<()> {
+        let duplicate = |name: &Ident| {
+            Error::new_spanned(name, format!("the {} clause can only be given once", name))
+        };
+        match clause {
+            Clause::Blocksize(name, expr) => {
+                if self.blocksize.is_some() {
+                    return Err(duplicate(&name));
+                }
+                self.blocksize = Some(expr);
+            }
+            Clause::Vars(name, vars) => {
+                for var in vars {
+                    self.check_var(&var)?;
+                    match name.to_string().as_str() {
+                        "shared" => self.shared.push(var),
+                        "shared_mut" => self.shared_mut.push(var),
+                        "shared_unsafe" => self.shared_unsafe.push(var),
+                        "split_mut" => self.split_mut.push(var),
+                        _ => self.private.push(var),
+                    }
+                }
+            }
+            Clause::Copyin(vars) => {
+                for var in vars {
+                    if self.copyin.contains(&var) {
+                        return Err(Error::new_spanned(
+                            &var,
+                            format!("`{}` is already listed in a copyin clause", var),
+                        ));
+                    }
+                    self.copyin.push(var);
+                }
+            }
+            Clause::Reduction(op, vars) => {
+                for var in vars {
+                    self.check_var(&var)?;
+                    self.reduction.push((var, op.clone()));
+                }
+            }
+            Clause::Fallible(name, res, ty) => {
+                if self.fallible.is_some() {
+                    return Err(duplicate(&name));
+                }
+                self.check_var(&res)?;
+                self.fallible = Some((res, ty));
+            }
+            Clause::Collect(name, out) => {
+                if self.collect.is_some() {
+                    return Err(duplicate(&name));
+                }
+                self.check_var(&out)?;
+                self.collect = Some(out);
+            }
+            Clause::Pool(name, pool) => {
+                if self.pool.is_some() {
+                    return Err(duplicate(&name));
+                }
+                self.pool = Some(pool);
+            }
+            Clause::Default(name, sharing) => {
+                if self.default.is_some() {
+                    return Err(duplicate(&name));
+                }
+                self.default = Some(sharing);
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the default clause to the outer variables used by the loop that are not listed in
+    /// any clause.
+    pub fn apply_default(&mut self, pat: &Pat, body: &Block) -> Result<()> {
+        let sharing = match self.default {
+            Some(DefaultSharing::Shared) | None => return Ok(()),
+            Some(sharing) => sharing,
+        };
+        let listed = self
+            .vars()
+            .map(|(v, _)| v)
+            .chain(&self.collect)
+            .chain(self.fallible.as_ref().map(|(res, _)| res))
+            .collect::<Vec<_>>();
+        let unlisted = free_variables(pat, body, &listed);
+        if sharing == DefaultSharing::Private {
+            self.private.extend(unlisted);
+            return Ok(());
+        }
+        let mut errors = unlisted.into_iter().map(|var| {
+            Error::new_spanned(
+                &var,
+                format!(
+                    "variable `{}` is not listed in any clause, which default(none) requires",
+                    var
+                ),
+            )
+        });
+        match errors.next() {
+            Some(mut error) => {
+                errors.for_each(|e| error.combine(e));
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Emits the clause state expected by `__internal_par_for!`, in its fixed order.
+    pub fn to_state(&self) -> TokenStream {
+        let blocksize = match &self.blocksize {
+            Some(expr) => quote!(#expr),
+            None => quote!(1),
+        };
+        let Clauses {
+            shared,
+            shared_mut,
+            shared_unsafe,
+            split_mut,
+            private,
+            copyin,
+            fallible,
+            collect,
+            pool,
+            ..
+        } = self;
+        let red_names = self.reduction.iter().map(|(v, _)| v);
+        let red_ops = self.reduction.iter().map(|(_, op)| op);
+        let fallible = fallible.iter().map(|(res, ty)| quote!(#res: #ty));
+        let collect = collect.iter();
+        let pool = pool.iter();
+        quote! {
+            blocksize(#blocksize),
+            shared_mut(#(#shared_mut)*),
+            shared(#(#shared)*),
+            shared_unsafe(#(#shared_unsafe)*),
+            split_mut(#(#split_mut)*),
+            private(#(#private)*),
+            copyin(#(#copyin)*),
+            reduction(#(#red_names, #red_ops)*),
+            fallible(#(#fallible)*),
+            collect(#(#collect)*),
+            pool(#(#pool)*),
+        }
+    }
+}
+
+impl Parse for Clauses {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut clauses = Clauses::default();
+        for clause in Punctuated::<Clause, Token![,]>::parse_terminated(input)? {
+            clauses.add(clause)?;
+        }
+        Ok(clauses)
+    }
+}
@@ -0,0 +1,172 @@
+//! Procedural front end for RustMP's parallel for loops.
+//!
+//! Clauses are written OpenMP style and may appear in any order:
+//!
+//! ```ignore
+//! #[rustmp::parallel_for(reduction(+: sum), shared_mut(result), blocksize(16))]
+//! fn kernel(...) {
+//!     for i in 0..n {
+//!         ...
+//!     }
+//! }
+//! ```
+//!
+//! Both forms expand to a single, fully normalized `rustmp::__internal_par_for!` call, so the
+//! generated runtime calls are the same as for `par_for!`, without its per-clause macro
+//! recursion.
+
+extern crate proc_macro;
+
+mod capture;
+mod clause;
+
+use clause::Clauses;
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Attribute, Error, Expr, ExprForLoop, ItemFn, Result, Stmt};
+
+fn expand(mut clauses: Clauses, lp: &ExprForLoop) -> Result<TokenStream2> {
+    if let Some(label) = &lp.label {
+        return Err(Error::new_spanned(
+            label,
+            "labels are not supported on parallel loops",
+        ));
+    }
+    let ExprForLoop {
+        pat, expr, body, ..
+    } = lp;
+    clauses.apply_default(pat, body)?;
+    let state = clauses.to_state();
+    Ok(quote! {
+        rustmp::__internal_par_for!(
+            var_name(#pat),
+            iterator(#expr),
+            #state
+            #body);
+    })
+}
+
+fn as_for_loop(stmt: &Stmt) -> Option<&ExprForLoop> {
+    match stmt {
+        Stmt::Expr(Expr::ForLoop(lp), _) => Some(lp),
+        _ => None,
+    }
+}
+
+/// Runs the only top level `for` loop of the annotated function on the thread pool.
+///
+/// Takes the same clauses as `parallel!`. Statements before and after the loop run on the
+/// calling thread as usual.
+#[proc_macro_attribute]
+pub fn parallel_for(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let clauses = parse_macro_input!(attr as Clauses);
+    let mut func = parse_macro_input!(item as ItemFn);
+    let mut loops = func
+        .block
+        .stmts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, stmt)| as_for_loop(stmt).map(|lp| (i, lp)));
+    let (index, lp) = match (loops.next(), loops.next()) {
+        (Some(found), None) => found,
+        (_, Some((_, second))) => {
+            return Error::new_spanned(
+                second,
+                "#[parallel_for] functions must contain exactly one top level for loop",
+            )
+            .to_compile_error()
+            .into()
+        }
+        (None, None) => {
+            return Error::new_spanned(
+                &func.sig,
+                "#[parallel_for] functions must contain a top level for loop",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+    let expanded = match expand(clauses, lp) {
+        Ok(expanded) => expanded,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    func.block.stmts[index] = Stmt::Item(syn::Item::Verbatim(expanded));
+    quote!(#func).into()
+}
+
+/// Body of `parallel!`: a for loop annotated with `#[parallel_for(...)]`.
+struct Parallel {
+    clauses: Clauses,
+    lp: ExprForLoop,
+}
+
+impl Parse for Parallel {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut attrs = input.call(Attribute::parse_outer)?;
+        let lp: ExprForLoop = input.parse()?;
+        if !input.is_empty() {
+            return Err(input.error("expected a single for loop"));
+        }
+        let attr = match attrs.len() {
+            1 if attrs[0].path().is_ident("parallel_for") => attrs.remove(0),
+            0 => {
+                return Err(Error::new_spanned(
+                    lp.for_token,
+                    "expected a #[parallel_for(...)] attribute before the loop",
+                ))
+            }
+            _ => {
+                return Err(Error::new_spanned(
+                    &attrs[attrs.len() - 1],
+                    "expected a single #[parallel_for(...)] attribute",
+                ))
+            }
+        };
+        let clauses = match attr.meta {
+            syn::Meta::Path(_) => Clauses::default(),
+            _ => attr.parse_args()?,
+        };
+        Ok(Parallel { clauses, lp })
+    }
+}
+
+/// Function-like form of `#[parallel_for]` for loops inside a larger function:
+///
+/// ```ignore
+/// rustmp::parallel! {
+///     #[parallel_for(shared_mut(result), reduction(+: sum))]
+///     for i in 0..n {
+///         ...
+///     }
+/// }
+/// ```
+///
+/// Clauses may appear in any order:
+/// - `blocksize(expr)`
+/// - `shared(a, ...)`, `shared_mut(a, ...)`, `shared_unsafe(a, ...)`, `split_mut(a, ...)`,
+///   `private(a, ...)`
+/// - `copyin(STATIC, ...)`, for statics declared with `threadprivate!`
+/// - `reduction(op: a, ...)`, where op is one of `+ * & | ^` or a function name
+/// - `fallible(name: ErrorType)`, binding the `Result<(), ErrorType>` of the loop to `name`
+/// - `collect(name)`
+/// - `pool(expr)`, to run on the given ThreadPool instead of the global pool
+/// - `default(shared | none | private)`: how variables used in the body but not listed in any
+///   clause are treated. `shared` borrows them, as without the clause. `none` makes each of them
+///   a compile error. `private` clones them into each thread.
+///
+/// Each variable may only appear in one clause, and all reduction variables of a loop must have
+/// the same type. The clauses behave as in `par_for!`.
+///
+/// The default clause finds variables syntactically: names starting with an uppercase letter and
+/// names called like functions are assumed not to be local variables. Code inside other macros
+/// is only checked when their arguments parse as expressions, as for `println!`.
+#[proc_macro]
+pub fn parallel(input: TokenStream) -> TokenStream {
+    let Parallel { clauses, lp } = parse_macro_input!(input as Parallel);
+    match expand(clauses, &lp) {
+        Ok(expanded) => expanded.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
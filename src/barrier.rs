@@ -0,0 +1,291 @@
+//! Topology aware completion of parallel regions.
+//!
+//! Every task of a region counts down the region's Latch when it finishes. With a single
+//! counter, every worker writes the same cache line, which bounces between packages on
+//! multi-socket machines at the end of every region. Instead, the Latch is a combining tree
+//! following the hwloc topology: workers count down the node of their core, the last one to
+//! arrive at a core counts down its package, and the last one to arrive at a package counts
+//! down the root. Only one thread per core touches a package's line, and only one thread per
+//! package touches the root's.
+//!
+//! Starting a region needs no barrier: each worker has its own task queue, see dispatch.rs.
+
+use crate::sysinfo::SystemObject;
+use crate::threadpool::spin_wait;
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::resume_unwind;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Shape of the combining tree of a team, shared by the Latches of its regions.
+pub(crate) struct BarrierTree {
+    /// Parent of each node, None for the root at index 0
+    parents: Vec<Option<usize>>,
+    /// Number of arrivals each node waits for
+    counts: Vec<usize>,
+    /// Leaf node of each thread of the team
+    leaves: Vec<usize>,
+    /// Counters of finished Latches, reset to counts, so starting a region does not allocate
+    spare: Mutex<Vec<Box<[Counter]>>>,
+}
+
+impl BarrierTree {
+    /// Builds the tree of a team whose thread i is bound to the hwthread pus[i].
+    pub fn new(pus: &[usize]) -> BarrierTree {
+        let system = SystemObject::get_instance();
+        let mut tree = BarrierTree::flat(0);
+        let mut packages = HashMap::new();
+        let mut cores = HashMap::new();
+        for &pu in pus {
+            let (package, core) = system.pu_location(pu);
+            let package = *packages.entry(package).or_insert_with(|| tree.add_node(0));
+            let core = *cores.entry(core).or_insert_with(|| tree.add_node(package));
+            tree.counts[core] += 1;
+            tree.leaves.push(core);
+        }
+        tree
+    }
+
+    /// Builds a tree with every thread arriving at the root, for teams without workers.
+    pub fn flat(num_threads: usize) -> BarrierTree {
+        BarrierTree {
+            parents: vec![None],
+            counts: vec![num_threads],
+            leaves: vec![0; num_threads],
+            spare: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns counters set to counts, reusing those of a finished Latch if there is one.
+    fn take_counters(&self) -> Box<[Counter]> {
+        self.spare.lock().unwrap().pop().unwrap_or_else(|| {
+            self.counts
+                .iter()
+                .map(|&count| Counter(AtomicUsize::new(count)))
+                .collect()
+        })
+    }
+
+    /// Resets the counters of a dropped Latch and keeps them for the next one.
+    fn return_counters(&self, counters: Box<[Counter]>) {
+        for (counter, &count) in counters.iter().zip(&self.counts) {
+            counter.0.store(count, Ordering::Relaxed);
+        }
+        self.spare.lock().unwrap().push(counters);
+    }
+
+    fn add_node(&mut self, parent: usize) -> usize {
+        self.parents.push(Some(parent));
+        self.counts.push(0);
+        self.counts[parent] += 1;
+        self.parents.len() - 1
+    }
+}
+
+/// Counter on its own cache line, 128 bytes to also cover adjacent line prefetching.
+#[repr(align(128))]
+struct Counter(AtomicUsize);
+
+/// Counts down the tasks of a region that have not finished yet.
+///
+/// finished can be polled without locking, so a waiting thread can spin on it before sleeping
+/// on done. The last task to finish takes the lock before notifying, so a thread that saw
+/// finished unset under the lock cannot miss the notification.
+pub(crate) struct Latch {
+    tree: Arc<BarrierTree>,
+    remaining: Box<[Counter]>,
+    finished: AtomicBool,
+    /// Waker of the task awaiting the RegionHandle, if any
+    waker: Mutex<Option<Waker>>,
+    done: Condvar,
+    /// First panic caught in a task, see PanicPolicy::Propagate
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl Latch {
+    pub fn new(tree: Arc<BarrierTree>) -> Latch {
+        Latch {
+            remaining: tree.take_counters(),
+            tree,
+            finished: AtomicBool::new(false),
+            waker: Mutex::new(None),
+            done: Condvar::new(),
+            panic: Mutex::new(None),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    /// Marks the task of thread_num as finished.
+    pub fn count_down(&self, thread_num: usize) {
+        let mut node = self.tree.leaves[thread_num];
+        // AcqRel chains the arrivals, so the thread finishing the root has seen every task's
+        // writes
+        while self.remaining[node].0.fetch_sub(1, Ordering::AcqRel) == 1 {
+            match self.tree.parents[node] {
+                Some(parent) => node = parent,
+                None => {
+                    let mut waker = self.waker.lock().unwrap();
+                    self.finished.store(true, Ordering::Release);
+                    self.done.notify_all();
+                    if let Some(waker) = waker.take() {
+                        waker.wake();
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Keeps the panic of a task, to be resumed once the region is done. Only the first one is
+    /// kept if several tasks panic.
+    pub fn set_panic(&self, payload: Box<dyn Any + Send>) {
+        self.panic.lock().unwrap().get_or_insert(payload);
+    }
+
+    fn resume_panic(&self) {
+        if let Some(payload) = self.panic.lock().unwrap().take() {
+            resume_unwind(payload);
+        }
+    }
+
+    /// Waits for every task to finish, following RMP_WAIT_POLICY, then resumes the panic of a
+    /// task, if any.
+    pub fn wait(&self) {
+        let spin_count = SystemObject::get_instance().spin_count;
+        if spin_wait(spin_count, || self.is_done().then_some(())).is_none() {
+            let mut waker = self.waker.lock().unwrap();
+            while !self.is_done() {
+                waker = self.done.wait(waker).unwrap();
+            }
+        }
+        self.resume_panic();
+    }
+
+    /// Returns Ready once every task has finished, or registers the task to wake otherwise.
+    /// Resumes the panic of a task, if any, instead of returning Ready.
+    pub fn poll(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut waker = self.waker.lock().unwrap();
+        if self.is_done() {
+            drop(waker);
+            self.resume_panic();
+            Poll::Ready(())
+        } else {
+            *waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Latch {
+    /// Hands the counters back to the tree. The Latch is only dropped once the thread waiting
+    /// for the region and every task are done with it, so nothing counts them down anymore.
+    fn drop(&mut self) {
+        self.tree
+            .return_counters(std::mem::take(&mut self.remaining));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    /// Two packages of two cores each, with two threads per core, built without hwloc.
+    fn two_level_tree() -> BarrierTree {
+        let mut tree = BarrierTree::flat(0);
+        for _ in 0..2 {
+            let package = tree.add_node(0);
+            for _ in 0..2 {
+                let core = tree.add_node(package);
+                tree.counts[core] = 2;
+                tree.leaves.extend([core, core]);
+            }
+        }
+        tree
+    }
+
+    /// Runs many small regions on num_threads threads counting down the same tree, checking
+    /// that each region only completes once every thread has arrived.
+    fn stress(tree: BarrierTree) {
+        const REGIONS: usize = 2_000;
+        let num_threads = tree.leaves.len();
+        let tree = Arc::new(tree);
+        for _ in 0..REGIONS {
+            let latch = Arc::new(Latch::new(tree.clone()));
+            let arrived = Arc::new(AtomicUsize::new(0));
+            let threads = (0..num_threads)
+                .map(|thread_num| {
+                    let latch = latch.clone();
+                    let arrived = arrived.clone();
+                    thread::spawn(move || {
+                        arrived.fetch_add(1, Ordering::Relaxed);
+                        latch.count_down(thread_num);
+                    })
+                })
+                .collect::<Vec<_>>();
+            latch.wait();
+            assert_eq!(arrived.load(Ordering::Relaxed), num_threads);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+        // Every region returned its counters, reset, and only a few were ever needed at once
+        assert!(tree.spare.lock().unwrap().len() <= 1);
+    }
+
+    #[test]
+    fn flat_tree_completes_every_region() {
+        stress(BarrierTree::flat(4));
+    }
+
+    #[test]
+    fn two_level_tree_completes_every_region() {
+        stress(two_level_tree());
+    }
+
+    #[test]
+    fn done_only_after_the_last_arrival() {
+        let tree = Arc::new(two_level_tree());
+        for last in 0..8 {
+            let latch = Latch::new(tree.clone());
+            for thread_num in (0..8).filter(|&thread_num| thread_num != last) {
+                latch.count_down(thread_num);
+                assert!(!latch.is_done());
+            }
+            latch.count_down(last);
+            assert!(latch.is_done());
+        }
+    }
+
+    #[test]
+    fn concurrent_regions_on_the_same_tree() {
+        let tree = Arc::new(BarrierTree::flat(2));
+        let first = Latch::new(tree.clone());
+        let second = Latch::new(tree.clone());
+        first.count_down(0);
+        second.count_down(0);
+        second.count_down(1);
+        assert!(!first.is_done());
+        assert!(second.is_done());
+        first.count_down(1);
+        assert!(first.is_done());
+    }
+
+    #[test]
+    fn wait_resumes_the_first_panic() {
+        let latch = Latch::new(Arc::new(BarrierTree::flat(2)));
+        latch.set_panic(Box::new("first"));
+        latch.set_panic(Box::new("second"));
+        latch.count_down(0);
+        latch.count_down(1);
+        let payload = catch_unwind(AssertUnwindSafe(|| latch.wait())).unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"first"));
+    }
+}
@@ -0,0 +1,32 @@
+use rayon::prelude::*;
+use std::cmp::max;
+use std::env;
+use std::hint::black_box;
+use std::time::Instant;
+
+fn warmup() {
+    let _discard = (0..1).into_par_iter().map(|i| i).collect::<Vec<i32>>();
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("Usage: {} <nregions>", args[0]);
+        return;
+    }
+    let nregions = max(
+        args[1].parse::<usize>().expect("Usage: launch <nregions>"),
+        1,
+    );
+    let nthreads = rayon::current_num_threads();
+    warmup();
+    let timer = Instant::now();
+    for _ in 0..nregions {
+        (0..nthreads).into_par_iter().for_each(|i| {
+            black_box(i);
+        });
+    }
+    let interval = timer.elapsed();
+    println!("Elapsed time: {:?}", interval);
+    println!("Per region: {:?}", interval / nregions as u32);
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_range_loop)]
+
 use rand::random;
 use rayon::prelude::*;
 use std::cmp::max;
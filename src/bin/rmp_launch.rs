@@ -0,0 +1,38 @@
+use std::cmp::max;
+use std::env;
+use std::hint::black_box;
+use std::time::Instant;
+
+use rustmp::par_for;
+
+fn warmup() {
+    par_for! {
+        for _ in 0..1, {
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("Usage: {} <nregions>", args[0]);
+        return;
+    }
+    let nregions = max(
+        args[1].parse::<usize>().expect("Usage: launch <nregions>"),
+        1,
+    );
+    let nthreads = rustmp::get_max_threads();
+    warmup();
+    let timer = Instant::now();
+    for _ in 0..nregions {
+        par_for! {
+            for i in 0..nthreads, {
+                black_box(i);
+            }
+        }
+    }
+    let interval = timer.elapsed();
+    println!("Elapsed time: {:?}", interval);
+    println!("Per region: {:?}", interval / nregions as u32);
+}
@@ -0,0 +1,174 @@
+//! Programmatic configuration of RustMP.
+//!
+//! RmpConfig sets the same options as the RMP_* environment variables, plus a few that have
+//! none, for libraries embedding RustMP that cannot rely on the environment:
+//!
+//! ```ignore
+//! rustmp::RmpConfig::builder()
+//!     .num_threads(8)
+//!     .wait_policy(rustmp::WaitPolicy::Active)
+//!     .name_prefix("solver-")
+//!     .build()
+//!     .init()
+//!     .expect("RustMP already started");
+//! ```
+//!
+//! The configuration is applied when RustMP starts, on the first parallel region or runtime
+//! query, so init() has to be called before either. Environment variables that are set
+//! override the matching options, so users can still tune a program without rebuilding it.
+//! The options also are the defaults of pools built with ThreadPoolBuilder.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Configuration set by init(), and whether RustMP has started and taken it.
+static PENDING: Mutex<(Option<RmpConfig>, bool)> = Mutex::new((None, false));
+
+/// How worker threads are pinned to hwthreads (PUs). Overridden by RMP_PROC_BIND=true|false.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindPolicy {
+    /// By core, then by hwthread on the same core, then by socket (the default)
+    Cores,
+    /// Worker tid on the hwthread with logical index cpus[tid % cpus.len()]
+    Cpus(Vec<usize>),
+    /// Workers are not pinned, the OS schedules them
+    Unbound,
+}
+
+/// What idle threads do while waiting for work. Overridden by RMP_WAIT_POLICY=active|passive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitPolicy {
+    /// Spin for RmpConfigBuilder::spin_count() polls, forever by default, before sleeping
+    Active,
+    /// Sleep right away (the default)
+    Passive,
+}
+
+/// What happens when a task panics on a worker thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Print the panic and exit the process with status 1 (the default)
+    Exit,
+    /// Print the panic and abort the process
+    Abort,
+    /// Resume the panic on the thread waiting for the region, once every task of the region
+    /// has finished
+    Propagate,
+}
+
+/// Options RustMP starts with, see the module documentation.
+#[derive(Clone, Debug, Default)]
+pub struct RmpConfig {
+    pub(crate) num_threads: Option<usize>,
+    pub(crate) max_active_levels: Option<usize>,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) bind: Option<BindPolicy>,
+    pub(crate) wait_policy: Option<WaitPolicy>,
+    pub(crate) spin_count: Option<usize>,
+    pub(crate) name_prefix: Option<String>,
+    pub(crate) panic_policy: Option<PanicPolicy>,
+}
+
+/// Builder of an RmpConfig, options left unset keep their defaults.
+#[derive(Clone, Debug, Default)]
+pub struct RmpConfigBuilder {
+    config: RmpConfig,
+}
+
+/// Error returned by RmpConfig::init() once RustMP has started.
+#[derive(Debug)]
+pub struct AlreadyInitialized;
+
+impl fmt::Display for AlreadyInitialized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RustMP has already started, configure it before its first use"
+        )
+    }
+}
+
+impl Error for AlreadyInitialized {}
+
+impl RmpConfig {
+    pub fn builder() -> RmpConfigBuilder {
+        RmpConfigBuilder::default()
+    }
+
+    /// Sets the configuration RustMP starts with, replacing any configuration set before.
+    ///
+    /// Returns an error if RustMP has already started.
+    pub fn init(self) -> Result<(), AlreadyInitialized> {
+        let mut pending = PENDING.lock().unwrap();
+        if pending.1 {
+            return Err(AlreadyInitialized);
+        }
+        pending.0 = Some(self);
+        Ok(())
+    }
+}
+
+impl RmpConfigBuilder {
+    /// Sets the number of threads of the global pool. Overridden by RMP_NUM_THREADS.
+    pub fn num_threads(mut self, num_threads: usize) -> RmpConfigBuilder {
+        self.config.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Sets the number of nested parallel regions running on their own team, counting the
+    /// outermost one, so 0 behaves like 1. Overridden by RMP_MAX_ACTIVE_LEVELS.
+    pub fn max_active_levels(mut self, max_active_levels: usize) -> RmpConfigBuilder {
+        self.config.max_active_levels = Some(max_active_levels);
+        self
+    }
+
+    /// Sets the stack size of worker threads in bytes. Overridden by RMP_STACKSIZE.
+    pub fn stack_size(mut self, stack_size: usize) -> RmpConfigBuilder {
+        self.config.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Sets how worker threads are pinned. Overridden by RMP_PROC_BIND.
+    pub fn bind(mut self, bind: BindPolicy) -> RmpConfigBuilder {
+        self.config.bind = Some(bind);
+        self
+    }
+
+    /// Sets what idle threads do. Overridden by RMP_WAIT_POLICY.
+    pub fn wait_policy(mut self, wait_policy: WaitPolicy) -> RmpConfigBuilder {
+        self.config.wait_policy = Some(wait_policy);
+        self
+    }
+
+    /// Sets how many times idle threads poll for work before sleeping, unless the wait
+    /// policy is passive. Overridden by RMP_SPINCOUNT.
+    pub fn spin_count(mut self, spin_count: usize) -> RmpConfigBuilder {
+        self.config.spin_count = Some(spin_count);
+        self
+    }
+
+    /// Sets the name of worker tid to the prefix followed by tid.
+    pub fn name_prefix(mut self, name_prefix: &str) -> RmpConfigBuilder {
+        self.config.name_prefix = Some(name_prefix.to_string());
+        self
+    }
+
+    /// Sets what happens when a task panics.
+    pub fn panic_policy(mut self, panic_policy: PanicPolicy) -> RmpConfigBuilder {
+        self.config.panic_policy = Some(panic_policy);
+        self
+    }
+
+    pub fn build(self) -> RmpConfig {
+        self.config
+    }
+}
+
+/// Returns the configuration set by init(), or the default one, and rejects later init()
+/// calls. Only called by SystemObject::new().
+pub(crate) fn take() -> RmpConfig {
+    let mut pending = PENDING.lock().unwrap();
+    pending.1 = true;
+    pending.0.take().unwrap_or_default()
+}
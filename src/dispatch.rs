@@ -0,0 +1,228 @@
+//! Single consumer task queue used to hand tasks to a worker.
+//!
+//! Replaces std::sync::mpsc for the worker channels: the worker polls a generation counter
+//! bumped by every send, so checking for work while spinning is a single atomic load, and a
+//! sleeping worker is woken with Thread::unpark(), a futex wake on Linux. Tasks are queued in
+//! send order, so regions submitted concurrently still run in the same order on every worker.
+//!
+//! As with mpsc, the receiver sees the queue as closed once every Sender has been dropped and
+//! the remaining tasks have been received.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{current, park, Thread};
+
+struct Slot<T> {
+    queue: Mutex<VecDeque<T>>,
+    /// Receiving thread, once it has waited for a task
+    waiter: OnceLock<Thread>,
+    /// Number of tasks sent so far
+    generation: AtomicUsize,
+    /// Set once every Sender has been dropped, after their last send
+    closed: AtomicBool,
+}
+
+/// Sending half of a task queue, cloning it shares the queue.
+pub(crate) struct Sender<T> {
+    inner: Arc<SenderInner<T>>,
+}
+
+/// Closes the queue once the last Sender is dropped.
+struct SenderInner<T> {
+    slot: Arc<Slot<T>>,
+}
+
+/// Receiving half of a task queue, owned by the worker.
+pub(crate) struct Receiver<T> {
+    slot: Arc<Slot<T>>,
+    /// Generation of the last task received
+    received: usize,
+}
+
+/// Outcome of a non blocking receive.
+pub(crate) enum TryRecv<T> {
+    Task(T),
+    Empty,
+    Closed,
+}
+
+/// Creates a task queue.
+pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let slot = Arc::new(Slot {
+        queue: Mutex::new(VecDeque::new()),
+        waiter: OnceLock::new(),
+        generation: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+    });
+    let sender = Sender {
+        inner: Arc::new(SenderInner { slot: slot.clone() }),
+    };
+    (sender, Receiver { slot, received: 0 })
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Queues task and wakes the receiver if it is sleeping.
+    pub fn send(&self, task: T) {
+        let slot = &self.inner.slot;
+        {
+            let mut queue = slot.queue.lock().unwrap();
+            queue.push_back(task);
+            slot.generation.fetch_add(1, Ordering::Release);
+        }
+        slot.wake();
+    }
+}
+
+impl<T> Drop for SenderInner<T> {
+    fn drop(&mut self) {
+        self.slot.closed.store(true, Ordering::Release);
+        self.slot.wake();
+    }
+}
+
+impl<T> Slot<T> {
+    /// Unparks the receiver, outside of the queue lock so it does not wake up only to block on
+    /// it. Unparking a thread that is not parked only makes its next park() return right away.
+    fn wake(&self) {
+        // Pairs with the fence in recv(): either the receiver sees the new generation, or
+        // this sees its waiter
+        fence(Ordering::SeqCst);
+        if let Some(waiter) = self.waiter.get() {
+            waiter.unpark();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Takes the next task without blocking.
+    pub fn try_recv(&mut self) -> TryRecv<T> {
+        // Read before the generation: once closed, every task sent is already counted
+        let closed = self.slot.closed.load(Ordering::Acquire);
+        if self.slot.generation.load(Ordering::Acquire) == self.received {
+            return if closed {
+                TryRecv::Closed
+            } else {
+                TryRecv::Empty
+            };
+        }
+        let task = self.slot.queue.lock().unwrap().pop_front().unwrap();
+        self.received += 1;
+        TryRecv::Task(task)
+    }
+
+    /// Sleeps until the next task arrives, returns None once the queue is closed and empty.
+    pub fn recv(&mut self) -> Option<T> {
+        self.slot.waiter.get_or_init(current);
+        fence(Ordering::SeqCst);
+        loop {
+            match self.try_recv() {
+                TryRecv::Task(task) => return Some(task),
+                TryRecv::Closed => return None,
+                // A send racing with this park() unparks the thread first, so park() returns
+                // right away instead of missing it
+                TryRecv::Empty => park(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn tasks_arrive_in_send_order() {
+        let (sender, mut receiver) = channel();
+        let producer = thread::spawn(move || {
+            for i in 0..100_000 {
+                sender.send(i);
+            }
+        });
+        for i in 0..100_000 {
+            assert_eq!(receiver.recv(), Some(i));
+        }
+        producer.join().unwrap();
+        assert_eq!(receiver.recv(), None);
+    }
+
+    #[test]
+    fn concurrent_senders() {
+        const SENDERS: usize = 4;
+        const TASKS: usize = 20_000;
+        let (sender, mut receiver) = channel();
+        let producers = (0..SENDERS)
+            .map(|id| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for i in 0..TASKS {
+                        sender.send((id, i));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(sender);
+        // Each sender's tasks stay in order, and the queue closes after the last one
+        let mut next = [0; SENDERS];
+        while let Some((id, i)) = receiver.recv() {
+            assert_eq!(i, next[id]);
+            next[id] += 1;
+        }
+        assert_eq!(next, [TASKS; SENDERS]);
+        for producer in producers {
+            producer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn try_recv_reports_empty_and_closed() {
+        let (sender, mut receiver) = channel();
+        assert!(matches!(receiver.try_recv(), TryRecv::Empty));
+        sender.send(1);
+        drop(sender);
+        // Tasks sent before closing are still received
+        assert!(matches!(receiver.try_recv(), TryRecv::Task(1)));
+        assert!(matches!(receiver.try_recv(), TryRecv::Closed));
+    }
+
+    #[test]
+    fn close_while_sleeping() {
+        for _ in 0..100 {
+            let (sender, mut receiver) = channel::<()>();
+            let consumer = thread::spawn(move || receiver.recv());
+            thread::sleep(Duration::from_micros(200));
+            drop(sender);
+            assert_eq!(consumer.join().unwrap(), None);
+        }
+    }
+
+    /// Many small round trips between two sleeping threads, each of which would hang on a lost
+    /// wakeup.
+    #[test]
+    fn ping_pong() {
+        let (ping, mut ping_rx) = channel();
+        let (pong, mut pong_rx) = channel();
+        let echo = thread::spawn(move || {
+            while let Some(i) = ping_rx.recv() {
+                pong.send(i);
+            }
+        });
+        for i in 0..20_000 {
+            ping.send(i);
+            assert_eq!(pong_rx.recv(), Some(i));
+        }
+        drop(ping);
+        echo.join().unwrap();
+        assert_eq!(pong_rx.recv(), None);
+    }
+}
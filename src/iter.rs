@@ -0,0 +1,252 @@
+//! Method chaining front end for the RustMP thread pool.
+//!
+//! RmpIterator adds rmp_for_each(), rmp_map() and rmp_reduce() to every IntoIterator. Work runs
+//! on the same pinned thread pool as par_for!, so results can be compared against both par_for!
+//! and rayon's parallel iterators. rmp_on() runs them on another ThreadPool instead, like the
+//! pool clause of par_for!.
+//!
+//! Unlike par_for!, items are handed out dynamically: workers take the next block of items from
+//! the shared iterator whenever they finish their current block. Block results are put back in
+//! iteration order, so collect() preserves order and rmp_reduce() only requires the reduction
+//! operation to be associative.
+//!
+//! par_for_async() hands items out the same way, but returns as soon as the loop is started,
+//! for callers running on an async executor.
+
+use crate::threadpool::{as_scoped_job, as_static_job, Job, RegionHandle, ThreadPool};
+use crate::LoopControl;
+use std::cmp::max;
+use std::convert::Infallible;
+use std::iter::FromIterator;
+use std::sync::{Arc, Mutex};
+
+/// Targeted number of blocks per thread, used to size blocks from the iterator's size_hint()
+const BLOCKS_PER_THREAD: usize = 4;
+
+/// Parallel iterator methods executed on the RustMP thread pool.
+pub trait RmpIterator: IntoIterator + Sized {
+    /// Calls f on every item, in parallel.
+    ///
+    /// Calling rustmp::cancel() from f skips every block not yet started.
+    fn rmp_for_each<F>(self, f: F)
+    where
+        Self::IntoIter: Send,
+        F: Fn(Self::Item) + Sync,
+    {
+        self.rmp_on(&ThreadPool::global()).rmp_for_each(f);
+    }
+
+    /// Lazily maps every item with f, see RmpMap for the operations running the map.
+    fn rmp_map<F, R>(self, f: F) -> RmpMap<Self::IntoIter, F>
+    where
+        F: Fn(Self::Item) -> R + Sync,
+    {
+        self.rmp_on(&ThreadPool::global()).rmp_map(f)
+    }
+
+    /// Reduces all items with op, in parallel.
+    ///
+    /// op must be associative, and identity() must return an identity element for op.
+    fn rmp_reduce<ID, OP>(self, identity: ID, op: OP) -> Self::Item
+    where
+        Self::IntoIter: Send,
+        Self::Item: Send,
+        ID: Fn() -> Self::Item + Sync,
+        OP: Fn(Self::Item, Self::Item) -> Self::Item + Sync,
+    {
+        self.rmp_on(&ThreadPool::global()).rmp_reduce(identity, op)
+    }
+
+    /// Runs the methods of the returned RmpOn on pool instead of the global pool.
+    fn rmp_on(self, pool: &ThreadPool) -> RmpOn<Self::IntoIter> {
+        RmpOn {
+            iter: self.into_iter(),
+            pool: pool.clone(),
+        }
+    }
+}
+
+impl<T: IntoIterator> RmpIterator for T {}
+
+/// An iterator bound to a thread pool by rmp_on(), with the methods of RmpIterator.
+pub struct RmpOn<I> {
+    iter: I,
+    pool: ThreadPool,
+}
+
+impl<I: Iterator> RmpOn<I> {
+    /// Calls f on every item, in parallel, see RmpIterator::rmp_for_each().
+    pub fn rmp_for_each<F>(self, f: F)
+    where
+        I: Send,
+        F: Fn(I::Item) + Sync,
+    {
+        drive(&self.pool, self.iter, |items| {
+            items.into_iter().for_each(&f)
+        });
+    }
+
+    /// Lazily maps every item with f, see RmpMap for the operations running the map.
+    pub fn rmp_map<F, R>(self, f: F) -> RmpMap<I, F>
+    where
+        F: Fn(I::Item) -> R + Sync,
+    {
+        RmpMap {
+            iter: self.iter,
+            f,
+            pool: self.pool,
+        }
+    }
+
+    /// Reduces all items with op, in parallel, see RmpIterator::rmp_reduce().
+    pub fn rmp_reduce<ID, OP>(self, identity: ID, op: OP) -> I::Item
+    where
+        I: Send,
+        I::Item: Send,
+        ID: Fn() -> I::Item + Sync,
+        OP: Fn(I::Item, I::Item) -> I::Item + Sync,
+    {
+        drive(&self.pool, self.iter, |items| {
+            items.into_iter().fold(identity(), &op)
+        })
+        .into_iter()
+        .fold(identity(), &op)
+    }
+}
+
+/// An iterator mapped by rmp_map().
+pub struct RmpMap<I, F> {
+    iter: I,
+    f: F,
+    pool: ThreadPool,
+}
+
+impl<I, F, R> RmpMap<I, F>
+where
+    I: Iterator + Send,
+    F: Fn(I::Item) -> R + Sync,
+    R: Send,
+{
+    /// Maps every item in parallel and collects the results in iteration order.
+    pub fn collect<C: FromIterator<R>>(self) -> C {
+        let f = self.f;
+        drive(&self.pool, self.iter, |items| {
+            items.into_iter().map(&f).collect::<Vec<R>>()
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Maps every item and reduces the results with op, in parallel.
+    ///
+    /// op must be associative, and identity() must return an identity element for op.
+    pub fn rmp_reduce<ID, OP>(self, identity: ID, op: OP) -> R
+    where
+        ID: Fn() -> R + Sync,
+        OP: Fn(R, R) -> R + Sync,
+    {
+        let f = self.f;
+        drive(&self.pool, self.iter, |items| {
+            items.into_iter().map(&f).fold(identity(), &op)
+        })
+        .into_iter()
+        .fold(identity(), &op)
+    }
+}
+
+/// Runs block on every block of items on pool, and returns the results in iteration order.
+fn drive<I, R, G>(pool: &ThreadPool, iter: I, block: G) -> Vec<R>
+where
+    I: Iterator + Send,
+    R: Send,
+    G: Fn(Vec<I::Item>) -> R + Sync,
+{
+    let tpm = pool.team();
+    let block_size = max(
+        iter.size_hint().0 / (tpm.num_threads * BLOCKS_PER_THREAD),
+        1,
+    );
+
+    // Next block index and the items left to hand out
+    let source = Mutex::new((0, iter.fuse()));
+    let results = Mutex::new(Vec::new());
+    let ctl = LoopControl::<Infallible>::new();
+    let tasks = (0..tpm.num_threads)
+        .map(|_| {
+            as_scoped_job(|| {
+                let _scope = ctl.enter();
+                let mut local = Vec::new();
+                while !ctl.stopped() {
+                    let (index, items) = {
+                        let mut source = source.lock().unwrap();
+                        let index = source.0;
+                        source.0 += 1;
+                        (
+                            index,
+                            source.1.by_ref().take(block_size).collect::<Vec<_>>(),
+                        )
+                    };
+                    if items.is_empty() {
+                        break;
+                    }
+                    local.push((index, block(items)));
+                }
+                results.lock().unwrap().append(&mut local);
+            })
+        })
+        .collect();
+    tpm.exec_scoped(tasks);
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_unstable_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Starts calling f on every item on the thread pool, and returns without waiting for it.
+///
+/// The returned RegionHandle is a Future completing once every item has been processed, so an
+/// async task can await the loop without blocking its executor thread, or it can be joined
+/// from synchronous code. The loop runs to completion even if the handle is dropped, which is
+/// why the items and f must be 'static: move or clone what the body needs into f, e.g. behind
+/// an Arc.
+///
+/// Calling rustmp::cancel() from f skips every block not yet started.
+pub fn par_for_async<I, F>(iter: I, f: F) -> RegionHandle
+where
+    I: IntoIterator,
+    I::IntoIter: Send + 'static,
+    F: Fn(I::Item) + Send + Sync + 'static,
+{
+    let team = ThreadPool::global().team();
+    let iter = iter.into_iter();
+    let block_size = max(
+        iter.size_hint().0 / (team.num_threads * BLOCKS_PER_THREAD),
+        1,
+    );
+
+    let source = Arc::new(Mutex::new(iter.fuse()));
+    let f = Arc::new(f);
+    let ctl = Arc::new(LoopControl::<Infallible>::new());
+    let tasks = (0..team.num_threads)
+        .map(|_| {
+            let (source, f, ctl) = (source.clone(), f.clone(), ctl.clone());
+            as_static_job(move || {
+                let _scope = ctl.enter();
+                while !ctl.stopped() {
+                    let items = source
+                        .lock()
+                        .unwrap()
+                        .by_ref()
+                        .take(block_size)
+                        .collect::<Vec<_>>();
+                    if items.is_empty() {
+                        break;
+                    }
+                    items.into_iter().for_each(|item| f(item));
+                }
+            })
+        })
+        .collect::<Vec<Job>>();
+    team.spawn_region(tasks)
+}
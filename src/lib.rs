@@ -1,11 +1,35 @@
+pub mod config;
+pub mod iter;
+pub mod race_check;
+pub mod runtime;
+pub mod split;
 pub mod threadpool;
 
+mod barrier;
+mod dispatch;
 mod sysinfo;
 
-use std::ops::{Deref, DerefMut};
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::mem::{size_of, size_of_val};
+use std::ops::{Deref, DerefMut, Index, IndexMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::LocalKey;
 
-pub use threadpool::{as_static_job, Job, ThreadPoolManager};
+pub use config::{
+    AlreadyInitialized, BindPolicy, PanicPolicy, RmpConfig, RmpConfigBuilder, WaitPolicy,
+};
+pub use iter::{par_for_async, RmpIterator};
+pub use runtime::{
+    get_level, get_max_active_levels, get_max_threads, get_num_procs, get_num_threads,
+    get_thread_num, in_parallel, set_max_active_levels, set_num_threads, shutdown,
+};
+pub use rustmp_macros::{parallel, parallel_for};
+pub use threadpool::{
+    as_scoped_job, as_static_job, Job, RegionHandle, ScopedJob, Team, ThreadPool,
+    ThreadPoolBuilder, ThreadPoolManager,
+};
 
 pub struct Capture<T> {
     value: Arc<RwLock<T>>,
@@ -18,18 +42,12 @@ impl<T> Capture<T> {
         }
     }
 
-    pub fn clone(&self) -> Capture<T> {
-        Capture {
-            value: Arc::clone(&self.value),
-        }
-    }
-
-    pub fn read(&self) -> RwLockReadGuard<T> {
-        return self.value.as_ref().read().unwrap();
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.value.as_ref().read().unwrap()
     }
 
-    pub fn write(&self) -> RwLockWriteGuard<T> {
-        return self.value.as_ref().write().unwrap();
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.value.as_ref().write().unwrap()
     }
 
     pub fn unwrap(self) -> T {
@@ -40,6 +58,14 @@ impl<T> Capture<T> {
     }
 }
 
+impl<T> Clone for Capture<T> {
+    fn clone(&self) -> Capture<T> {
+        Capture {
+            value: Arc::clone(&self.value),
+        }
+    }
+}
+
 pub struct UnsafePtr<T> {
     pub value: *mut T,
 }
@@ -56,7 +82,7 @@ impl<T> Copy for UnsafePtr<T> {}
 
 impl<T> Clone for UnsafePtr<T> {
     fn clone(&self) -> Self {
-        UnsafePtr { value: self.value }
+        *self
     }
 }
 
@@ -64,19 +90,253 @@ impl<T> Deref for UnsafePtr<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
+        race_check::record(self.value as usize, size_of::<T>(), false);
         unsafe { self.value.as_ref().unwrap() }
     }
 }
 
 impl<T> DerefMut for UnsafePtr<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
+        race_check::record(self.value as usize, size_of::<T>(), true);
         unsafe { self.value.as_mut().unwrap() }
     }
 }
 
+impl<T: Index<I>, I> Index<I> for UnsafePtr<T> {
+    type Output = T::Output;
+
+    fn index(&self, index: I) -> &Self::Output {
+        let target = unsafe { self.value.as_ref().unwrap() };
+        let elem = &target[index];
+        race_check::record(
+            elem as *const _ as *const () as usize,
+            size_of_val(elem),
+            false,
+        );
+        elem
+    }
+}
+
+impl<T: IndexMut<I>, I> IndexMut<I> for UnsafePtr<T> {
+    fn index_mut(&mut self, index: I) -> &mut Self::Output {
+        let target = unsafe { self.value.as_mut().unwrap() };
+        let elem = &mut target[index];
+        race_check::record(
+            elem as *const _ as *const () as usize,
+            size_of_val(elem),
+            true,
+        );
+        elem
+    }
+}
+
 unsafe impl<T> Send for UnsafePtr<T> {}
 unsafe impl<T> Sync for UnsafePtr<T> {}
 
+/// Hands out mutable references to the elements of a slice, at most once per element.
+///
+/// Used by the split_mut clause of par_for!: each iteration claims the element at its loop
+/// index, so workers write to disjoint elements without locks. Claiming the same index twice
+/// panics instead of creating aliasing references.
+pub struct SplitMut<'a, T> {
+    ptr: *mut T,
+    claimed: Vec<AtomicBool>,
+    _slice: PhantomData<&'a mut [T]>,
+}
+
+impl<'a, T> SplitMut<'a, T> {
+    pub fn new(slice: &'a mut [T]) -> SplitMut<'a, T> {
+        SplitMut {
+            ptr: slice.as_mut_ptr(),
+            claimed: (0..slice.len()).map(|_| AtomicBool::new(false)).collect(),
+            _slice: PhantomData,
+        }
+    }
+
+    /// Returns the element at index.
+    ///
+    /// Panics if index is out of bounds or if the element has already been claimed.
+    pub fn claim(&self, index: usize) -> &'a mut T {
+        if self.claimed[index].swap(true, Ordering::Relaxed) {
+            panic!(
+                "Error: split_mut element {} accessed by more than one iteration",
+                index
+            );
+        }
+        // Safety: index is in bounds and the swap above guarantees that no other reference to
+        // this element has been handed out.
+        unsafe { &mut *self.ptr.add(index) }
+    }
+}
+
+unsafe impl<T: Send> Send for SplitMut<'_, T> {}
+unsafe impl<T: Send> Sync for SplitMut<'_, T> {}
+
+/// Value with one persistent copy per thread, declared with threadprivate!.
+///
+/// Pool workers are long lived, so each worker's copy survives across par_for! loops, which
+/// makes it a good place for scratch buffers that should only be allocated once. The copyin
+/// clause of par_for! overwrites the worker copies with the value of the thread starting the
+/// loop.
+pub struct ThreadPrivate<T: 'static> {
+    key: LocalKey<RefCell<T>>,
+}
+
+impl<T: 'static> ThreadPrivate<T> {
+    #[doc(hidden)]
+    pub const fn new(key: LocalKey<RefCell<T>>) -> ThreadPrivate<T> {
+        ThreadPrivate { key }
+    }
+
+    /// Runs f with the calling thread's copy of the value.
+    ///
+    /// Panics if called again from within f for the same variable.
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&'static self, f: F) -> R {
+        self.key.with(|value| f(&mut value.borrow_mut()))
+    }
+
+    /// Replaces the calling thread's copy of the value.
+    pub fn set(&'static self, value: T) {
+        self.key.with(|current| *current.borrow_mut() = value);
+    }
+}
+
+impl<T: Clone + 'static> ThreadPrivate<T> {
+    /// Returns a clone of the calling thread's copy of the value.
+    pub fn get(&'static self) -> T {
+        self.key.with(|value| value.borrow().clone())
+    }
+
+    /// Captures the calling thread's value for the copyin clause of the loop it is starting.
+    #[doc(hidden)]
+    pub fn copyin(&'static self) -> Copyin<T> {
+        Copyin {
+            var: self,
+            value: Mutex::new(self.get()),
+        }
+    }
+}
+
+/// Value of a threadprivate! static on the thread starting a loop, owned by the loop.
+#[doc(hidden)]
+pub struct Copyin<T: 'static> {
+    var: &'static ThreadPrivate<T>,
+    value: Mutex<T>,
+}
+
+/// Sets the calling thread's copy of a static to the value captured for the current loop.
+#[doc(hidden)]
+pub trait ApplyCopyin: Sync {
+    fn apply(&self);
+}
+
+impl<T: Clone + Send + 'static> ApplyCopyin for Copyin<T> {
+    fn apply(&self) {
+        let value = self.value.lock().unwrap().clone();
+        self.var.set(value);
+    }
+}
+
+thread_local! {
+    /// Stop flag of the loop the current thread is executing, used by cancel().
+    static CURRENT_LOOP: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+/// Cancels the par_for! loop the calling thread is executing.
+///
+/// Workers check for cancellation at chunk (blocksize) boundaries, so iterations of the
+/// current chunk still run to completion while every chunk not yet started is skipped.
+/// Has no effect when called outside of a par_for! body.
+pub fn cancel() {
+    CURRENT_LOOP.with(|current| {
+        if let Some(stop) = current.borrow().as_ref() {
+            stop.store(true, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Returns true if the par_for! loop the calling thread is executing has been cancelled.
+///
+/// Can be used as an explicit cancellation point inside long running iterations.
+pub fn cancelled() -> bool {
+    CURRENT_LOOP.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .is_some_and(|stop| stop.load(Ordering::Relaxed))
+    })
+}
+
+/// Per-loop state shared by the workers of a par_for! region.
+///
+/// Holds the first error raised by a fallible loop body, and the stop flag set by errors and
+/// cancel(). Workers check the flag before picking up their next chunk of iterations.
+pub struct LoopControl<E> {
+    stop: Arc<AtomicBool>,
+    error: Mutex<Option<E>>,
+}
+
+impl<E> LoopControl<E> {
+    pub fn new() -> LoopControl<E> {
+        LoopControl {
+            stop: Arc::new(AtomicBool::new(false)),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// Returns true once the remaining iterations should be skipped.
+    pub fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// Cancels the loop, skipping every chunk that has not been started yet.
+    pub fn cancel(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Records an error raised by an iteration and cancels the loop.
+    ///
+    /// Only the first error is kept, later ones are dropped.
+    pub fn raise(&self, err: E) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(err);
+        }
+        self.cancel();
+    }
+
+    /// Takes the error raised by the loop, if any.
+    pub fn take_error(&self) -> Option<E> {
+        self.error.lock().unwrap().take()
+    }
+
+    /// Registers this loop as the target of cancel() on the calling thread.
+    ///
+    /// The previous target is restored when the returned guard is dropped.
+    pub fn enter(&self) -> LoopScope {
+        let prev = CURRENT_LOOP.with(|current| current.replace(Some(self.stop.clone())));
+        LoopScope { prev }
+    }
+}
+
+impl<E> Default for LoopControl<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Guard returned by LoopControl::enter().
+pub struct LoopScope {
+    prev: Option<Arc<AtomicBool>>,
+}
+
+impl Drop for LoopScope {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT_LOOP.with(|current| current.replace(prev));
+    }
+}
+
 #[macro_export]
 macro_rules! critical {
     (read $($r:ident)+; readwrite $($w:ident)+; $($ops:tt)+) => {
@@ -115,100 +375,259 @@ macro_rules! __reduction_operation {
     }
 }
 
+#[macro_export]
+macro_rules! __loop_error_type {
+    () => {
+        std::convert::Infallible
+    };
+    ($err:ty) => {
+        $err
+    };
+}
+
+#[macro_export]
+macro_rules! __pool_team {
+    () => {
+        rustmp::ThreadPool::global().team()
+    };
+    ($pool:expr) => {
+        rustmp::ThreadPool::team(&$pool)
+    };
+}
+
+#[macro_export]
+macro_rules! __collect_type {
+    () => {
+        ()
+    };
+    ($out:ident) => {
+        _
+    };
+}
+
+#[macro_export]
+macro_rules! __internal_par_for_push {
+    (collect(), $buffer:ident, $index:ident, $value:ident) => {
+        let () = $value;
+    };
+    (collect($out:ident), $buffer:ident, $index:ident, $value:ident) => {
+        $buffer.push(($index, $value));
+    };
+}
+
+#[macro_export]
+macro_rules! __internal_par_for_bind {
+    (collect($($out:ident)?), fallible(), $collected:ident, $error:ident) => {
+        $(let $out = $collected;)?
+        let _ = $error;
+    };
+    (collect($($out:ident)?), fallible($res:ident: $err:ty), $collected:ident, $error:ident) => {
+        $(let $out = $collected;)?
+        let $res: Result<(), $err> = match $error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    };
+}
+
+#[macro_export]
+macro_rules! __internal_par_for_loop {
+    (fallible(),
+    collect($($out:ident)?),
+    split_mut($($split_mut:ident)*),
+    $name:pat, $iter:ident, $size:ident, $tid:ident, $ctl:ident, $collector:ident, $log:ident,
+    $blk:block) => {
+        let _scope = $ctl.enter();
+        let mut __rmp_buffer = Vec::new();
+        for (__rmp_index, __rmp_item) in $iter {
+            if __rmp_index % $size == 0 && $ctl.stopped() {
+                break;
+            }
+            rustmp::race_check::set_iteration(&$log, $tid, __rmp_index);
+            $(let $split_mut = $split_mut.claim(__rmp_item);)*
+            let $name = __rmp_item;
+            let __rmp_value = $blk;
+            rustmp::__internal_par_for_push!(
+                collect($($out)?), __rmp_buffer, __rmp_index, __rmp_value);
+        }
+        rustmp::race_check::clear_iteration();
+        $collector.submit($tid, __rmp_buffer);
+    };
+    (fallible($res:ident: $err:ty),
+    collect($($out:ident)?),
+    split_mut($($split_mut:ident)*),
+    $name:pat, $iter:ident, $size:ident, $tid:ident, $ctl:ident, $collector:ident, $log:ident,
+    $blk:block) => {
+        let _scope = $ctl.enter();
+        let mut __rmp_buffer = Vec::new();
+        let __rmp_res = (|| -> Result<(), $err> {
+            for (__rmp_index, __rmp_item) in $iter {
+                if __rmp_index % $size == 0 && $ctl.stopped() {
+                    break;
+                }
+                rustmp::race_check::set_iteration(&$log, $tid, __rmp_index);
+                $(let $split_mut = $split_mut.claim(__rmp_item);)*
+                let $name = __rmp_item;
+                let __rmp_iter_res: Result<_, $err> = $blk;
+                let __rmp_value = __rmp_iter_res?;
+                rustmp::__internal_par_for_push!(
+                    collect($($out)?), __rmp_buffer, __rmp_index, __rmp_value);
+            }
+            Ok(())
+        })();
+        rustmp::race_check::clear_iteration();
+        $collector.submit($tid, __rmp_buffer);
+        if let Err(e) = __rmp_res {
+            $ctl.raise(e);
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! __internal_par_for {
     // without reduction
-    (var_name($name:ident),
+    (var_name($name:pat),
     iterator($iter:expr),
     blocksize($size:expr),
     shared_mut($($shared_mut:ident)*),
     shared($($shared:ident)*),
     shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
     private($($private:ident)*),
+    copyin($($copyin:ident)*),
     reduction(),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
     $blk:block) => {
         $(let $shared_mut = rustmp::Capture::new($shared_mut);)*
-        {
-            let mut __rmp_tasks = Vec::new();
-            $(let $shared = std::sync::Arc::new($shared.clone());)*
+        let (__rmp_collected, __rmp_error) = {
+            $(let $shared = &$shared;)*
             $(let $shared_unsafe = rustmp::UnsafePtr::new(&mut $shared_unsafe);)*
-            let __rmp_tpm_mtx = rustmp::ThreadPoolManager::get_instance_guard();
-            let __rmp_tpm = __rmp_tpm_mtx.lock().unwrap();
-            let __rmp_iters = __rmp_tpm.split_iterators($iter, $size);
-            for iter in __rmp_iters {
-                $(let $shared_mut = $shared_mut.clone();)*
-                $(let $shared = $shared.clone();)*
-                $(let $private = $private.clone();)*
-                __rmp_tasks.push(rustmp::as_static_job(move || {
+            $(let $split_mut = rustmp::SplitMut::new(&mut $split_mut[..]);)*
+            let __rmp_ctl = rustmp::LoopControl::<rustmp::__loop_error_type!($($err)?)>::new();
+            let __rmp_tpm = rustmp::__pool_team!($($pool)?);
+            let __rmp_copyin: Vec<Box<dyn rustmp::ApplyCopyin>> =
+                vec![$(Box::new($copyin.copyin()),)*];
+            let __rmp_log = rustmp::race_check::begin_loop();
+            let __rmp_size: usize = $size;
+            let __rmp_collector = rustmp::split::Collector::<rustmp::__collect_type!($($out)?)>::new(
+                __rmp_tpm.num_threads, __rmp_size);
+            let __rmp_iters = {
+                use rustmp::split::{SplitIndexed as _, SplitSequential as _};
+                (&rustmp::split::Splitter::new(std::iter::IntoIterator::into_iter($iter)))
+                    .split(&__rmp_tpm, __rmp_size)
+            };
+            let mut __rmp_tasks: Vec<rustmp::ScopedJob<'_>> = Vec::new();
+            for __rmp_task in __rmp_iters.into_iter().enumerate() {
+                __rmp_tasks.push(rustmp::as_scoped_job(|| {
+                    let (__rmp_tid, iter) = __rmp_task;
+                    for __rmp_value in &__rmp_copyin {
+                        __rmp_value.apply();
+                    }
                     $(let mut $private = $private.clone();)*
                     $(let mut $shared_unsafe = $shared_unsafe.clone();)*
-                    for &$name in &iter
-                        $blk
+                    rustmp::__internal_par_for_loop!(
+                        fallible($($res: $err)?),
+                        collect($($out)?),
+                        split_mut($($split_mut)*),
+                        $name, iter, __rmp_size, __rmp_tid, __rmp_ctl, __rmp_collector, __rmp_log, $blk);
                 }));
             }
-            __rmp_tpm.exec(__rmp_tasks);
-        }
+            __rmp_tpm.exec_scoped(__rmp_tasks);
+            rustmp::race_check::end_loop(__rmp_log);
+            (__rmp_collector.into_vec(), __rmp_ctl.take_error())
+        };
         $(let $shared_mut = $shared_mut.unwrap();)*
+        rustmp::__internal_par_for_bind!(
+            collect($($out)?), fallible($($res: $err)?), __rmp_collected, __rmp_error);
     };
 
     // with reduction
-    (var_name($name:ident),
+    (var_name($name:pat),
     iterator($iter:expr),
     blocksize($size:expr),
     shared_mut($($shared_mut:ident)*),
     shared($($shared:ident)*),
     shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
     private($($private:ident)*),
+    copyin($($copyin:ident)*),
     reduction($($red_name:ident, $red_op:tt)+),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
     $blk:block) => {
         $(let $shared_mut = rustmp::Capture::new($shared_mut);)*
-        {
-            let mut __rmp_tasks = Vec::new();
-            $(let $shared = std::sync::Arc::new($shared.clone());)*
+        let (__rmp_collected, __rmp_error) = {
+            $(let $shared = &$shared;)*
             $(let $shared_unsafe = rustmp::UnsafePtr::new(&mut $shared_unsafe);)*
-            let __rmp_tpm_mtx = rustmp::ThreadPoolManager::get_instance_guard();
-            let __rmp_tpm = __rmp_tpm_mtx.lock().unwrap();
-            let __rmp_iters = __rmp_tpm.split_iterators($iter, $size);
+            $(let $split_mut = rustmp::SplitMut::new(&mut $split_mut[..]);)*
+            let __rmp_ctl = rustmp::LoopControl::<rustmp::__loop_error_type!($($err)?)>::new();
+            let __rmp_tpm = rustmp::__pool_team!($($pool)?);
+            let __rmp_copyin: Vec<Box<dyn rustmp::ApplyCopyin>> =
+                vec![$(Box::new($copyin.copyin()),)*];
+            let __rmp_log = rustmp::race_check::begin_loop();
+            let __rmp_size: usize = $size;
+            let __rmp_collector = rustmp::split::Collector::<rustmp::__collect_type!($($out)?)>::new(
+                __rmp_tpm.num_threads, __rmp_size);
+            let __rmp_iters = {
+                use rustmp::split::{SplitIndexed as _, SplitSequential as _};
+                (&rustmp::split::Splitter::new(std::iter::IntoIterator::into_iter($iter)))
+                    .split(&__rmp_tpm, __rmp_size)
+            };
             let mut __rmp_red_vals = Vec::new();
             $(__rmp_red_vals.push(Vec::new()); stringify!($red_name);)*
             let __rmp_red_vals = rustmp::Capture::new(__rmp_red_vals);
-            for iter in __rmp_iters {
-                $(let $shared_mut = $shared_mut.clone();)*
-                $(let $shared = $shared.clone();)*
-                $(let $private = $private.clone();)*
-                let __rmp_red_vals = __rmp_red_vals.clone();
-                $(let $red_name = $red_name.clone();)*
-                __rmp_tasks.push(rustmp::as_static_job(move || {
+            let mut __rmp_tasks: Vec<rustmp::ScopedJob<'_>> = Vec::new();
+            for __rmp_task in __rmp_iters.into_iter().enumerate() {
+                __rmp_tasks.push(rustmp::as_scoped_job(|| {
+                    let (__rmp_tid, iter) = __rmp_task;
+                    for __rmp_value in &__rmp_copyin {
+                        __rmp_value.apply();
+                    }
                     $(let mut $private = $private.clone();)*
                     $(let mut $shared_unsafe = $shared_unsafe.clone();)*
                     $(let mut $red_name = $red_name.clone();)*
-                    for &$name in &iter
-                        $blk
+                    rustmp::__internal_par_for_loop!(
+                        fallible($($res: $err)?),
+                        collect($($out)?),
+                        split_mut($($split_mut)*),
+                        $name, iter, __rmp_size, __rmp_tid, __rmp_ctl, __rmp_collector, __rmp_log, $blk);
                     let mut __rmp_counter = 0;
                     let mut __rmp_temp = __rmp_red_vals.write();
                     $(__rmp_temp[__rmp_counter].push($red_name); __rmp_counter += 1;)*
                 }));
             }
-            __rmp_tpm.exec(__rmp_tasks);
+            __rmp_tpm.exec_scoped(__rmp_tasks);
+            rustmp::race_check::end_loop(__rmp_log);
             let mut __rmp_temp = __rmp_red_vals.read();
             let mut __rmp_counter = 0;
             $($red_name = __rmp_temp[__rmp_counter]
                 .iter()
                 .fold($red_name, rustmp::__reduction_operation!($red_op));
             __rmp_counter += 1;)*
-        }
+            (__rmp_collector.into_vec(), __rmp_ctl.take_error())
+        };
         $(let $shared_mut = $shared_mut.unwrap();)*
+        rustmp::__internal_par_for_bind!(
+            collect($($out)?), fallible($($res: $err)?), __rmp_collected, __rmp_error);
     };
 
     // Parse blocksize
-    (var_name($name:ident),
+    (var_name($name:pat),
     iterator($iter:expr),
     blocksize($size:expr),
     shared_mut($($shared_mut:ident)*),
     shared($($shared:ident)*),
     shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
     private($($private:ident)*),
+    copyin($($copyin:ident)*),
     reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
     blocksize $new_size:expr,
     $($rem:tt)+) => {
         rustmp::__internal_par_for!(
@@ -218,20 +637,30 @@ macro_rules! __internal_par_for {
             shared_mut($($shared_mut)*),
             shared($($shared)*),
             shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
             private($($private)*),
+            copyin($($copyin)*),
             reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($($pool)?),
             $($rem)*)
     };
 
     // Parse shared_mut
-    (var_name($name:ident),
+    (var_name($name:pat),
     iterator($iter:expr),
     blocksize($size:expr),
     shared_mut($($shared_mut:ident)*),
     shared($($shared:ident)*),
     shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
     private($($private:ident)*),
+    copyin($($copyin:ident)*),
     reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
     shared_mut $($new_shared_mut:ident)*,
     $($rem:tt)+) => {
         rustmp::__internal_par_for!(
@@ -241,20 +670,30 @@ macro_rules! __internal_par_for {
             shared_mut($($new_shared_mut)*),
             shared($($shared)*),
             shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
             private($($private)*),
+            copyin($($copyin)*),
             reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($($pool)?),
             $($rem)*)
     };
 
     // Parse shared
-    (var_name($name:ident),
+    (var_name($name:pat),
     iterator($iter:expr),
     blocksize($size:expr),
     shared_mut($($shared_mut:ident)*),
     shared($($shared:ident)*),
     shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
     private($($private:ident)*),
+    copyin($($copyin:ident)*),
     reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
     shared $($new_name:ident)*,
     $($rem:tt)+) => {
         rustmp::__internal_par_for!(
@@ -264,20 +703,30 @@ macro_rules! __internal_par_for {
             shared_mut($($shared_mut)*),
             shared($($new_name)*),
             shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
             private($($private)*),
+            copyin($($copyin)*),
             reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($($pool)?),
             $($rem)*)
     };
 
     // Parse shared_unsafe
-    (var_name($name:ident),
+    (var_name($name:pat),
     iterator($iter:expr),
     blocksize($size:expr),
     shared_mut($($shared_mut:ident)*),
     shared($($shared:ident)*),
     shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
     private($($private:ident)*),
+    copyin($($copyin:ident)*),
     reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
     shared_unsafe $($new_shared_unsafe:ident)*,
     $($rem:tt)+) => {
         rustmp::__internal_par_for!(
@@ -287,20 +736,63 @@ macro_rules! __internal_par_for {
             shared_mut($($shared_mut)*),
             shared($($shared)*),
             shared_unsafe($($new_shared_unsafe)*),
+            split_mut($($split_mut)*),
             private($($private)*),
+            copyin($($copyin)*),
             reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($($pool)?),
+            $($rem)*)
+    };
+
+    // Parse split_mut
+    (var_name($name:pat),
+    iterator($iter:expr),
+    blocksize($size:expr),
+    shared_mut($($shared_mut:ident)*),
+    shared($($shared:ident)*),
+    shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
+    private($($private:ident)*),
+    copyin($($copyin:ident)*),
+    reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
+    split_mut $($new_split_mut:ident)*,
+    $($rem:tt)+) => {
+        rustmp::__internal_par_for!(
+            var_name($name),
+            iterator($iter),
+            blocksize($size),
+            shared_mut($($shared_mut)*),
+            shared($($shared)*),
+            shared_unsafe($($shared_unsafe)*),
+            split_mut($($new_split_mut)*),
+            private($($private)*),
+            copyin($($copyin)*),
+            reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($($pool)?),
             $($rem)*)
     };
 
     // Parse private
-    (var_name($name:ident),
+    (var_name($name:pat),
     iterator($iter:expr),
     blocksize($size:expr),
     shared_mut($($shared_mut:ident)*),
     shared($($shared:ident)*),
     shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
     private($($private:ident)*),
+    copyin($($copyin:ident)*),
     reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
     private $($new_private:ident)*,
     $($rem:tt)+) => {
         rustmp::__internal_par_for!(
@@ -310,20 +802,162 @@ macro_rules! __internal_par_for {
             shared_mut($($shared_mut)*),
             shared($($shared)*),
             shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
             private($($new_private)*),
+            copyin($($copyin)*),
+            reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($($pool)?),
+            $($rem)*)
+    };
+
+    // Parse fallible
+    (var_name($name:pat),
+    iterator($iter:expr),
+    blocksize($size:expr),
+    shared_mut($($shared_mut:ident)*),
+    shared($($shared:ident)*),
+    shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
+    private($($private:ident)*),
+    copyin($($copyin:ident)*),
+    reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
+    fallible into $new_res:ident: $new_err:ty,
+    $($rem:tt)+) => {
+        rustmp::__internal_par_for!(
+            var_name($name),
+            iterator($iter),
+            blocksize($size),
+            shared_mut($($shared_mut)*),
+            shared($($shared)*),
+            shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
+            private($($private)*),
+            copyin($($copyin)*),
+            reduction($($red_name, $red_op)*),
+            fallible($new_res: $new_err),
+            collect($($out)?),
+            pool($($pool)?),
+            $($rem)*)
+    };
+
+    // Parse collect into
+    (var_name($name:pat),
+    iterator($iter:expr),
+    blocksize($size:expr),
+    shared_mut($($shared_mut:ident)*),
+    shared($($shared:ident)*),
+    shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
+    private($($private:ident)*),
+    copyin($($copyin:ident)*),
+    reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
+    collect into $new_out:ident,
+    $($rem:tt)+) => {
+        rustmp::__internal_par_for!(
+            var_name($name),
+            iterator($iter),
+            blocksize($size),
+            shared_mut($($shared_mut)*),
+            shared($($shared)*),
+            shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
+            private($($private)*),
+            copyin($($copyin)*),
+            reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($new_out),
+            pool($($pool)?),
+            $($rem)*)
+    };
+
+    // Parse copyin
+    (var_name($name:pat),
+    iterator($iter:expr),
+    blocksize($size:expr),
+    shared_mut($($shared_mut:ident)*),
+    shared($($shared:ident)*),
+    shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
+    private($($private:ident)*),
+    copyin($($copyin:ident)*),
+    reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
+    copyin $($new_copyin:ident)*,
+    $($rem:tt)+) => {
+        rustmp::__internal_par_for!(
+            var_name($name),
+            iterator($iter),
+            blocksize($size),
+            shared_mut($($shared_mut)*),
+            shared($($shared)*),
+            shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
+            private($($private)*),
+            copyin($($new_copyin)*),
             reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($($pool)?),
+            $($rem)*)
+    };
+
+    // Parse pool
+    (var_name($name:pat),
+    iterator($iter:expr),
+    blocksize($size:expr),
+    shared_mut($($shared_mut:ident)*),
+    shared($($shared:ident)*),
+    shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
+    private($($private:ident)*),
+    copyin($($copyin:ident)*),
+    reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
+    pool $new_pool:expr,
+    $($rem:tt)+) => {
+        rustmp::__internal_par_for!(
+            var_name($name),
+            iterator($iter),
+            blocksize($size),
+            shared_mut($($shared_mut)*),
+            shared($($shared)*),
+            shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
+            private($($private)*),
+            copyin($($copyin)*),
+            reduction($($red_name, $red_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($new_pool),
             $($rem)*)
     };
 
     // Parse reduction
-    (var_name($name:ident),
+    (var_name($name:pat),
     iterator($iter:expr),
     blocksize($size:expr),
     shared_mut($($shared_mut:ident)*),
     shared($($shared:ident)*),
     shared_unsafe($($shared_unsafe:ident)*),
+    split_mut($($split_mut:ident)*),
     private($($private:ident)*),
+    copyin($($copyin:ident)*),
     reduction($($red_name:ident, $red_op:tt)*),
+    fallible($($res:ident: $err:ty)?),
+    collect($($out:ident)?),
+    pool($($pool:expr)?),
     reduction $($new_name:ident#$new_op:tt);*,
     $($rem:tt)+) => {
         rustmp::__internal_par_for!(
@@ -333,8 +967,13 @@ macro_rules! __internal_par_for {
             shared_mut($($shared_mut)*),
             shared($($shared)*),
             shared_unsafe($($shared_unsafe)*),
+            split_mut($($split_mut)*),
             private($($private)*),
+            copyin($($copyin)*),
             reduction($($new_name, $new_op)*),
+            fallible($($res: $err)?),
+            collect($($out)?),
+            pool($($pool)?),
             $($rem)*)
     };
 
@@ -342,11 +981,54 @@ macro_rules! __internal_par_for {
 
 /// "parallel for" wrapper
 ///
+/// With the `fallible into <name>: <ErrorType>` clause, the loop body must evaluate to
+/// `Result<(), ErrorType>` and may use `?`. The first error raised stops the remaining
+/// iterations, and a `Result<(), ErrorType>` holding it, or Ok(()) if every iteration
+/// succeeded, is bound to `name` after the loop. With `collect into`, the body evaluates to
+/// `Result<T, ErrorType>` instead, and the values of the iterations that succeeded are
+/// collected.
+///
+/// The loop iterates over anything implementing IntoIterator, and items are moved into the
+/// workers by value, so collections of owned values such as `Vec<String>` can be consumed
+/// directly. The loop variable can be any irrefutable pattern, e.g.
+/// `for (i, row) in rows.into_iter().enumerate()`.
+///
+/// The `split_mut` clause gives each iteration exclusive access to one element of a slice,
+/// array or Vec: inside the body the variable is a `&mut` to the element at the loop index,
+/// which must be a usize. For a `Vec<Vec<T>>` this is a row; for other chunk shapes, split_mut
+/// a `Vec` of `chunks_mut()`.
+///
+/// With `collect into <name>`, the body evaluates to a value for each iteration, and a Vec of
+/// these values in iteration order is bound to `name` after the loop. par_map! is a shorthand
+/// evaluating to that Vec directly.
+///
+/// The `copyin` clause takes statics declared with threadprivate!, and sets the copy of each
+/// worker to the value of the calling thread before the loop starts.
+///
+/// The `pool <expr>` clause runs the loop on the given ThreadPool instead of the global pool.
+///
+/// Loops started from several threads at once share the workers of their pool: each worker
+/// runs its part of the loops in the order they were started.
+///
+/// A par_for! inside of another par_for! body runs serially on the worker executing that body,
+/// unless RMP_MAX_ACTIVE_LEVELS (or set_max_active_levels()) allows more than one active level,
+/// in which case it runs on a nested team, see ThreadPool::team().
+///
+/// Calling `rustmp::cancel()` from the body cancels the loop: chunks that have not been
+/// started on any worker are skipped.
+///
+/// Loop bodies run on the thread pool while the calling thread waits, so they can borrow
+/// variables from the enclosing scope directly. Read-only data does not need to be listed in a
+/// `shared` clause, which only borrows its variables.
+///
+/// The `default(none)` check for unlisted variables is only available through the `parallel!`
+/// front end, which can inspect the loop body.
+///
 /// If the number of arguments increases, convert this to a tail recursive parser instead.
 /// Current implementation save limited (max depth 32) stack space for macro expansion.
 #[macro_export]
 macro_rules! par_for {
-    (for $name:ident in $iter:expr, $($rem:tt)+) => {
+    (for $name:pat in $iter:expr, $($rem:tt)+) => {
         rustmp::__internal_par_for!(
             var_name($name),
             iterator($iter),
@@ -354,21 +1036,176 @@ macro_rules! par_for {
             shared_mut(),
             shared(),
             shared_unsafe(),
+            split_mut(),
             private(),
+            copyin(),
             reduction(),
+            fallible(),
+            collect(),
+            pool(),
             $($rem)*)
     };
+}
 
-    (for _ in $iter:expr, $($rem:tt)+) => {
-        rustmp::__internal_par_for!(
-            var_name(__rmp_internal_unused),
-            iterator($iter),
-            blocksize(1),
-            shared_mut(),
-            shared(),
-            shared_unsafe(),
-            private(),
-            reduction(),
-            $($rem)*)
+/// "parallel map" wrapper
+///
+/// Same syntax as par_for!, but the body evaluates to a value for each iteration and the macro
+/// evaluates to a Vec of these values in iteration order. The shared_mut clause is rejected,
+/// as its variables would be moved into the macro and dropped with it: use par_for! with
+/// `collect into` instead.
+///
+/// With `fallible into <name>: <ErrorType>`, the body evaluates to `Result<T, ErrorType>` and
+/// the macro to `Result<Vec<T>, ErrorType>`, holding the first error raised if any.
+#[macro_export]
+macro_rules! par_map {
+    (for $name:pat in $iter:expr, $($rem:tt)+) => {
+        rustmp::__internal_par_map!(for $name in $iter, clauses(), fallible(), $($rem)+)
+    };
+}
+
+/// Forwards the clauses of par_map! to par_for!, keeping the name of the fallible result.
+#[macro_export]
+macro_rules! __internal_par_map {
+    (for $name:pat in $iter:expr, clauses($($clauses:tt)*), fallible($($res:ident)?),
+    shared_mut $($rem:tt)+) => {
+        compile_error!(
+            "par_map! does not support shared_mut, use par_for! with collect into instead"
+        );
+    };
+    (for $name:pat in $iter:expr, clauses($($clauses:tt)*), fallible(),
+    fallible into $res:ident: $err:ty, $($rem:tt)+) => {
+        rustmp::__internal_par_map!(
+            for $name in $iter,
+            clauses($($clauses)* fallible into $res: $err,),
+            fallible($res),
+            $($rem)+)
+    };
+    (for $name:pat in $iter:expr, clauses($($clauses:tt)*), fallible(), $blk:block) => {
+        {
+            rustmp::par_for!(for $name in $iter, $($clauses)* collect into __rmp_map_out, $blk);
+            __rmp_map_out
+        }
+    };
+    (for $name:pat in $iter:expr, clauses($($clauses:tt)*), fallible($res:ident), $blk:block) => {
+        {
+            rustmp::par_for!(for $name in $iter, $($clauses)* collect into __rmp_map_out, $blk);
+            $res.map(|()| __rmp_map_out)
+        }
+    };
+    (for $name:pat in $iter:expr, clauses($($clauses:tt)*), fallible($($res:ident)?),
+    $next:tt $($rem:tt)+) => {
+        rustmp::__internal_par_map!(
+            for $name in $iter,
+            clauses($($clauses)* $next),
+            fallible($($res)?),
+            $($rem)+)
+    };
+}
+
+/// Declares statics with one persistent copy per thread, see ThreadPrivate.
+///
+/// ```ignore
+/// threadprivate! {
+///     static SCRATCH: Vec<f64> = Vec::new();
+/// }
+///
+/// par_for! {
+///     for i in 0..n, copyin SCRATCH, {
+///         SCRATCH.with(|scratch| scratch.resize(1024, 0.0));
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! threadprivate {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: rustmp::ThreadPrivate<$ty> = {
+                std::thread_local! {
+                    static __RMP_VALUE: std::cell::RefCell<$ty> = std::cell::RefCell::new($init);
+                }
+                rustmp::ThreadPrivate::new(__RMP_VALUE)
+            };
+        )+
+    };
+}
+
+/// "parallel for" returning a Future, see rustmp::par_for_async().
+///
+/// The body is moved into the workers and must only use 'static data, so clauses are not
+/// supported. The macro evaluates to a RegionHandle, which can be awaited or joined.
+///
+/// ```ignore
+/// let data = Arc::new(data);
+/// rustmp::par_for_async! {
+///     for i in 0..n, {
+///         process(&data[i]);
+///     }
+/// }
+/// .await;
+/// ```
+#[macro_export]
+macro_rules! par_for_async {
+    (for $name:pat in $iter:expr, $blk:block) => {
+        rustmp::par_for_async($iter, move |$name| $blk)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SplitMut;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn split_mut_claims_every_element_once() {
+        let mut values = [0; 16];
+        let split = SplitMut::new(&mut values[..]);
+        for i in (0..16).rev() {
+            *split.claim(i) = i * 10;
+        }
+        drop(split);
+        assert!(values.iter().enumerate().all(|(i, &value)| value == i * 10));
+    }
+
+    #[test]
+    #[should_panic(expected = "split_mut element 3 accessed by more than one iteration")]
+    fn split_mut_second_claim_panics() {
+        let mut values = [0; 8];
+        let split = SplitMut::new(&mut values[..]);
+        let _first = split.claim(3);
+        let _second = split.claim(3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn split_mut_out_of_bounds_panics() {
+        let mut values = [0; 8];
+        SplitMut::new(&mut values[..]).claim(8);
+    }
+
+    #[test]
+    fn split_mut_concurrent_claims_succeed_once() {
+        let mut values = [0usize; 16];
+        let split = SplitMut::new(&mut values[..]);
+        let claims = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let (split, claims) = (&split, &claims);
+                s.spawn(move || {
+                    // Every thread tries to claim every element, only one of them may get it
+                    for i in 0..16 {
+                        if let Ok(value) = catch_unwind(AssertUnwindSafe(|| split.claim(i))) {
+                            *value += t + 1;
+                            claims.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        drop(split);
+        assert_eq!(claims.into_inner(), 16);
+        assert!(values.iter().all(|&value| (1..=4).contains(&value)));
     }
 }
@@ -0,0 +1,368 @@
+//! Race detector for shared_unsafe accesses.
+//!
+//! When RustMP is built with the "race-check" feature, every access made through an UnsafePtr
+//! inside a par_for! body is logged together with the worker and the iteration that made it.
+//! At the end of the loop, accesses from different iterations to overlapping addresses where at
+//! least one of them is a write are reported and the program panics.
+//!
+//! Indexing an UnsafePtr (e.g. result[i][j]) is logged at the granularity of the first index:
+//! two iterations taking a mutable reference to the same row conflict even if they go on to
+//! write different columns, since the aliasing mutable references are already unsound.
+//!
+//! Each loop logs to its own LoopLog, so loops running concurrently on different threads are
+//! checked independently.
+//!
+//! Without the feature, all functions in this module are no-ops.
+
+#[cfg(feature = "race-check")]
+use std::cell::RefCell;
+#[cfg(feature = "race-check")]
+use std::collections::HashMap;
+#[cfg(feature = "race-check")]
+use std::sync::{Arc, Mutex};
+
+/// Maximum number of conflicts printed in a report
+#[cfg(feature = "race-check")]
+const MAX_REPORTED: usize = 16;
+
+#[cfg(feature = "race-check")]
+#[derive(Clone, Copy, PartialEq)]
+struct Access {
+    addr: usize,
+    len: usize,
+    write: bool,
+    thread: usize,
+    iteration: usize,
+}
+
+#[cfg(feature = "race-check")]
+type AccessLog = Arc<Mutex<Vec<Access>>>;
+
+/// Accesses logged during one par_for! loop.
+pub struct LoopLog {
+    #[cfg(feature = "race-check")]
+    accesses: AccessLog,
+}
+
+/// Loop body a thread is executing, with the accesses it made that are not in the log yet.
+#[cfg(feature = "race-check")]
+struct Current {
+    log: AccessLog,
+    thread: usize,
+    iteration: usize,
+    buffer: Vec<Access>,
+}
+
+#[cfg(feature = "race-check")]
+impl Current {
+    /// Moves the buffered accesses to the loop's log, taking its lock once per chunk instead
+    /// of once per access.
+    fn flush(self) {
+        self.log.lock().unwrap().extend(self.buffer);
+    }
+}
+
+#[cfg(feature = "race-check")]
+thread_local! {
+    /// Loop body executed by this thread, None outside of a loop body
+    static CURRENT: RefCell<Option<Current>> = const { RefCell::new(None) };
+}
+
+/// Creates the access log of a par_for! loop about to start.
+#[inline(always)]
+pub fn begin_loop() -> LoopLog {
+    LoopLog {
+        #[cfg(feature = "race-check")]
+        accesses: Arc::new(Mutex::new(Vec::new())),
+    }
+}
+
+/// Marks the calling worker as executing the given iteration of the loop logging to log.
+#[inline(always)]
+pub fn set_iteration(log: &LoopLog, thread: usize, iteration: usize) {
+    #[cfg(feature = "race-check")]
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        match current.as_mut() {
+            Some(cur) if Arc::ptr_eq(&cur.log, &log.accesses) => {
+                cur.thread = thread;
+                cur.iteration = iteration;
+            }
+            _ => {
+                let prev = current.replace(Current {
+                    log: log.accesses.clone(),
+                    thread,
+                    iteration,
+                    buffer: Vec::new(),
+                });
+                if let Some(prev) = prev {
+                    prev.flush();
+                }
+            }
+        }
+    });
+    #[cfg(not(feature = "race-check"))]
+    let _ = (log, thread, iteration);
+}
+
+/// Marks the calling worker as no longer executing a loop body.
+#[inline(always)]
+pub fn clear_iteration() {
+    #[cfg(feature = "race-check")]
+    CURRENT.with(|current| {
+        if let Some(cur) = current.borrow_mut().take() {
+            cur.flush();
+        }
+    });
+}
+
+/// Logs an access of len bytes at addr made from the current iteration.
+///
+/// Repeating the last access of the iteration, as a loop over the same element does, is only
+/// logged once.
+#[inline(always)]
+pub fn record(addr: usize, len: usize, write: bool) {
+    #[cfg(feature = "race-check")]
+    CURRENT.with(|current| {
+        if let Some(cur) = current.borrow_mut().as_mut() {
+            let access = Access {
+                addr,
+                len,
+                write,
+                thread: cur.thread,
+                iteration: cur.iteration,
+            };
+            if cur.buffer.last() != Some(&access) {
+                cur.buffer.push(access);
+            }
+        }
+    });
+    #[cfg(not(feature = "race-check"))]
+    let _ = (addr, len, write);
+}
+
+/// Accesses to the same bytes, with at most one writer and one reader per iteration.
+#[cfg(feature = "race-check")]
+struct Location {
+    addr: usize,
+    len: usize,
+    writers: Vec<Access>,
+    readers: Vec<Access>,
+}
+
+#[cfg(feature = "race-check")]
+impl Location {
+    fn overlaps(&self, other: &Location) -> bool {
+        other.addr < self.addr + self.len
+    }
+}
+
+/// Groups the accesses by location, dropping the ones repeated in the same iteration.
+#[cfg(feature = "race-check")]
+fn locations(accesses: Vec<Access>) -> Vec<Location> {
+    let mut locations = HashMap::new();
+    for access in accesses {
+        let location = locations
+            .entry((access.addr, access.len))
+            .or_insert_with(|| Location {
+                addr: access.addr,
+                len: access.len,
+                writers: Vec::new(),
+                readers: Vec::new(),
+            });
+        if access.write {
+            location.writers.push(access);
+        } else {
+            location.readers.push(access);
+        }
+    }
+    let mut locations: Vec<Location> = locations.into_values().collect();
+    for location in &mut locations {
+        for accesses in [&mut location.writers, &mut location.readers] {
+            accesses.sort_by_key(|access| access.iteration);
+            accesses.dedup_by_key(|access| access.iteration);
+        }
+    }
+    locations.sort_by_key(|location| location.addr);
+    locations
+}
+
+/// Returns a write and another access made by different iterations, if there is one.
+///
+/// Linear in the number of accesses: if every other access has the iteration of the first
+/// write, a conflict can only involve another write, with any of the other accesses.
+#[cfg(feature = "race-check")]
+fn find_conflict<'a>(
+    writers: &'a [Access],
+    others: &'a [Access],
+) -> Option<(&'a Access, &'a Access)> {
+    let first = writers.first()?;
+    if let Some(other) = others
+        .iter()
+        .find(|other| other.iteration != first.iteration)
+    {
+        return Some((first, other));
+    }
+    let other = others.first()?;
+    let write = writers
+        .iter()
+        .find(|write| write.iteration != other.iteration)?;
+    Some((write, other))
+}
+
+/// Checks the accesses logged during the loop and panics if any of them conflict.
+///
+/// Reports at most one conflict per location, or per pair of overlapping locations.
+#[inline(always)]
+pub fn end_loop(log: LoopLog) {
+    #[cfg(not(feature = "race-check"))]
+    let _ = log;
+    #[cfg(feature = "race-check")]
+    {
+        let locations = locations(std::mem::take(&mut *log.accesses.lock().unwrap()));
+
+        let mut conflicts = 0;
+        let mut report = |conflict: Option<(&Access, &Access)>| {
+            if let Some((first, second)) = conflict {
+                if conflicts < MAX_REPORTED {
+                    eprintln!(
+                        "race-check: {} of {} bytes at {:#x} by thread #{} (iteration {}) \
+                         conflicts with {} of {} bytes at {:#x} by thread #{} (iteration {})",
+                        access_kind(first),
+                        first.len,
+                        first.addr,
+                        first.thread,
+                        first.iteration,
+                        access_kind(second),
+                        second.len,
+                        second.addr,
+                        second.thread,
+                        second.iteration
+                    );
+                }
+                conflicts += 1;
+            }
+        };
+        for (i, first) in locations.iter().enumerate() {
+            report(
+                find_conflict(&first.writers, &first.writers)
+                    .or_else(|| find_conflict(&first.writers, &first.readers)),
+            );
+            for second in locations[i + 1..]
+                .iter()
+                .take_while(|second| first.overlaps(second))
+            {
+                report(
+                    find_conflict(&first.writers, &second.writers)
+                        .or_else(|| find_conflict(&first.writers, &second.readers))
+                        .or_else(|| find_conflict(&second.writers, &first.readers)),
+                );
+            }
+        }
+
+        if conflicts > 0 {
+            panic!(
+                "race-check: {} conflicting shared_unsafe accesses detected",
+                conflicts
+            );
+        }
+    }
+}
+
+#[cfg(feature = "race-check")]
+fn access_kind(access: &Access) -> &'static str {
+    if access.write {
+        "write"
+    } else {
+        "read"
+    }
+}
+
+#[cfg(all(test, feature = "race-check"))]
+mod tests {
+    use super::*;
+
+    /// Runs a loop whose iterations make the given (iteration, addr, len, write) accesses, in
+    /// order, from two threads, then checks it.
+    fn check(accesses: &[(usize, usize, usize, bool)]) {
+        let log = begin_loop();
+        for &(iteration, addr, len, write) in accesses {
+            set_iteration(&log, iteration % 2, iteration);
+            record(addr, len, write);
+        }
+        clear_iteration();
+        end_loop(log);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 conflicting shared_unsafe accesses")]
+    fn write_write_conflict() {
+        check(&[(0, 0x1000, 8, true), (1, 0x1000, 8, true)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 conflicting shared_unsafe accesses")]
+    fn read_write_conflict() {
+        check(&[(0, 0x1000, 8, false), (1, 0x1000, 8, true)]);
+    }
+
+    #[test]
+    fn reads_do_not_conflict() {
+        check(&[(0, 0x1000, 8, false), (1, 0x1000, 8, false)]);
+    }
+
+    #[test]
+    fn disjoint_element_writes() {
+        // result[i] = ... for every i, then reading result[i] back in the same iteration
+        let accesses = (0..10_000)
+            .flat_map(|i| [(i, 0x1000 + 8 * i, 8, true), (i, 0x1000 + 8 * i, 8, false)])
+            .collect::<Vec<_>>();
+        check(&accesses);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 conflicting shared_unsafe accesses")]
+    fn whole_row_against_element() {
+        // *row = ... through Deref in iteration 0, row[5] read in iteration 3
+        check(&[(0, 0x1000, 64, true), (3, 0x1028, 8, false)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 conflicting shared_unsafe accesses")]
+    fn element_against_a_row_starting_earlier() {
+        // Locations between the row and the element in address order must not end the scan
+        check(&[
+            (0, 0x1000, 64, false),
+            (0, 0x1000, 8, true),
+            (0, 0x1010, 8, true),
+            (6, 0x1038, 8, true),
+        ]);
+    }
+
+    #[test]
+    fn neighbouring_rows() {
+        check(&[(0, 0x1000, 64, true), (1, 0x1040, 64, true)]);
+    }
+
+    #[test]
+    fn repeated_accesses_of_one_iteration() {
+        let mut accesses = Vec::new();
+        for _ in 0..100 {
+            accesses.extend([(2, 0x1000, 8, true), (2, 0x1000, 64, false)]);
+            accesses.extend([(2, 0x1008, 8, false), (2, 0x1008, 8, true)]);
+        }
+        accesses.push((3, 0x2000, 8, true));
+        check(&accesses);
+    }
+
+    #[test]
+    #[should_panic(expected = "2 conflicting shared_unsafe accesses")]
+    fn one_report_per_location() {
+        let mut accesses = Vec::new();
+        for i in 0..100 {
+            accesses.extend([(i, 0x1000, 8, true), (i, 0x2000, 8, false)]);
+        }
+        accesses.push((7, 0x2000, 8, true));
+        check(&accesses);
+    }
+}
@@ -0,0 +1,142 @@
+//! OpenMP style runtime queries and settings.
+//!
+//! These can be called from anywhere. Inside a par_for! body (or any task run by the
+//! ThreadPoolManager) they describe the enclosing parallel region, outside of one they describe
+//! the sequential part of the program, the same way omp_get_thread_num() and friends do.
+
+use crate::sysinfo::SystemObject;
+use crate::threadpool::ThreadPool;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Parallel region the current thread is executing a task of.
+#[derive(Clone, Copy)]
+pub(crate) struct Region {
+    pub thread_num: usize,
+    pub num_threads: usize,
+    pub level: usize,
+}
+
+const SEQUENTIAL: Region = Region {
+    thread_num: 0,
+    num_threads: 1,
+    level: 0,
+};
+
+/// Size of the thread pool, mirrored here so it can be read without locking the pool.
+/// 0 until the pool has been created.
+static MAX_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn set_max_threads(num_threads: usize) {
+    MAX_THREADS.store(num_threads, Ordering::Relaxed);
+}
+
+/// Overrides RMP_MAX_ACTIVE_LEVELS once set, usize::MAX until then.
+static MAX_ACTIVE_LEVELS: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+thread_local! {
+    static CURRENT_REGION: Cell<Region> = const { Cell::new(SEQUENTIAL) };
+}
+
+/// Returns the region the calling thread is executing a task of.
+pub(crate) fn current_region() -> Region {
+    CURRENT_REGION.with(|region| region.get())
+}
+
+/// Marks the calling thread as running a task of region until the guard is dropped.
+pub(crate) fn enter_region(region: Region) -> RegionGuard {
+    RegionGuard {
+        prev: CURRENT_REGION.with(|current| current.replace(region)),
+    }
+}
+
+pub(crate) struct RegionGuard {
+    prev: Region,
+}
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        CURRENT_REGION.with(|current| current.set(self.prev));
+    }
+}
+
+/// Returns the number of the calling thread within its parallel region, from 0 to
+/// get_num_threads() - 1. Returns 0 outside of a parallel region.
+pub fn get_thread_num() -> usize {
+    current_region().thread_num
+}
+
+/// Returns the number of threads executing the current parallel region, or 1 outside of one.
+pub fn get_num_threads() -> usize {
+    current_region().num_threads
+}
+
+/// Returns the number of threads the next parallel region will run on.
+pub fn get_max_threads() -> usize {
+    match MAX_THREADS.load(Ordering::Relaxed) {
+        0 => SystemObject::get_instance().max_num_threads,
+        num_threads => num_threads,
+    }
+}
+
+/// Sets the number of threads subsequent parallel regions run on, overriding RMP_NUM_THREADS.
+///
+/// Regions already running keep their number of threads. Panics if called from inside a
+/// parallel region, or if num_threads is 0.
+pub fn set_num_threads(num_threads: usize) {
+    assert!(
+        !in_parallel(),
+        "Error: set_num_threads() called inside a parallel region"
+    );
+    ThreadPool::global().set_num_threads(num_threads);
+}
+
+/// Stops the workers of the global pool and waits for them to exit, after they have run the
+/// regions already started. Nested pools owned by the workers are shut down with them.
+///
+/// The workers are spawned again by the next parallel region on the global pool. Panics if
+/// called from inside a parallel region.
+pub fn shutdown() {
+    assert!(
+        !in_parallel(),
+        "Error: shutdown() called inside a parallel region"
+    );
+    ThreadPool::global().shutdown();
+}
+
+/// Returns the maximum number of nested parallel regions that run on their own team of
+/// threads, RMP_MAX_ACTIVE_LEVELS by default. Regions nested deeper run serially on the thread
+/// starting them.
+pub fn get_max_active_levels() -> usize {
+    match MAX_ACTIVE_LEVELS.load(Ordering::Relaxed) {
+        usize::MAX => SystemObject::get_instance().max_active_levels,
+        max_active_levels => max_active_levels,
+    }
+}
+
+/// Sets the maximum number of nested parallel regions that run on their own team of threads,
+/// overriding RMP_MAX_ACTIVE_LEVELS.
+///
+/// The count includes the outermost region, which always runs on its pool, so 0 behaves like
+/// 1, the default, where nested regions run serially.
+pub fn set_max_active_levels(max_active_levels: usize) {
+    MAX_ACTIVE_LEVELS.store(
+        max_active_levels.clamp(1, usize::MAX - 1),
+        Ordering::Relaxed,
+    );
+}
+
+/// Returns true if the calling thread is executing a parallel region.
+pub fn in_parallel() -> bool {
+    current_region().level > 0
+}
+
+/// Returns the number of parallel regions enclosing the calling thread, 0 outside of any.
+pub fn get_level() -> usize {
+    current_region().level
+}
+
+/// Returns the number of hwthreads (PUs) available on the machine.
+pub fn get_num_procs() -> usize {
+    SystemObject::get_instance().available_hwthreads
+}
@@ -0,0 +1,468 @@
+//! Splitting of par_for! iteration spaces across the threads of the pool.
+//!
+//! Iterations are dealt out in blocks of block_size, round-robin over the threads: thread t
+//! runs blocks t, t + num_threads, t + 2 * num_threads, ... Each thread receives a partition,
+//! an iterator over (global iteration index, item) pairs for its blocks.
+//!
+//! Sources implementing IndexedSource (integer ranges, slice iterators) are split lazily, each
+//! partition only holds the source and computes its own indices. Any other iterator falls back
+//! to ThreadPoolManager::split_iterators(), which drains it into one Vec per thread first.
+
+use crate::threadpool::Team;
+use std::cell::Cell;
+use std::cmp::min;
+use std::ops::{Range, RangeInclusive};
+use std::slice;
+use std::sync::Mutex;
+use std::vec;
+
+/// A random-access iteration source that can be split across threads without draining it.
+pub trait IndexedSource {
+    type Item;
+
+    /// Number of items in the source.
+    fn len(&self) -> usize;
+
+    /// Returns true if the source has no items.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the item at index, which is always less than len().
+    fn get(&self, index: usize) -> Self::Item;
+}
+
+/// Integer types usable as the bounds of an IndexedSource range.
+pub trait RangeInt: Copy + PartialOrd {
+    /// Number of integers in start..end, or 0 if end <= start.
+    fn distance(start: Self, end: Self) -> usize;
+
+    /// Returns start + index.
+    fn offset(start: Self, index: usize) -> Self;
+}
+
+macro_rules! impl_range_int {
+    ($($t:ty)*) => {
+        $(
+            impl RangeInt for $t {
+                fn distance(start: $t, end: $t) -> usize {
+                    if start < end {
+                        (end as i128 - start as i128) as usize
+                    } else {
+                        0
+                    }
+                }
+
+                fn offset(start: $t, index: usize) -> $t {
+                    (start as i128 + index as i128) as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_range_int!(u8 u16 u32 u64 usize i8 i16 i32 i64 isize);
+
+impl<T: RangeInt> IndexedSource for Range<T> {
+    type Item = T;
+
+    fn len(&self) -> usize {
+        T::distance(self.start, self.end)
+    }
+
+    fn get(&self, index: usize) -> T {
+        T::offset(self.start, index)
+    }
+}
+
+impl<T: RangeInt> IndexedSource for RangeInclusive<T> {
+    type Item = T;
+
+    fn len(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            T::distance(*self.start(), *self.end()) + 1
+        }
+    }
+
+    fn get(&self, index: usize) -> T {
+        T::offset(*self.start(), index)
+    }
+}
+
+impl<'a, T> IndexedSource for slice::Iter<'a, T> {
+    type Item = &'a T;
+
+    fn len(&self) -> usize {
+        ExactSizeIterator::len(self)
+    }
+
+    fn get(&self, index: usize) -> &'a T {
+        &self.as_slice()[index]
+    }
+}
+
+/// A thread's share of an IndexedSource.
+pub struct IndexedPartition<T> {
+    source: T,
+    len: usize,
+    block_size: usize,
+    stride: usize,
+    block_start: usize,
+    block_end: usize,
+    index: usize,
+}
+
+impl<T: IndexedSource> Iterator for IndexedPartition<T> {
+    type Item = (usize, T::Item);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index == self.block_end {
+            self.block_start = self.block_start.checked_add(self.stride)?;
+            if self.block_start >= self.len {
+                return None;
+            }
+            self.index = self.block_start;
+            self.block_end = min(self.block_start.saturating_add(self.block_size), self.len);
+        }
+        let index = self.index;
+        self.index += 1;
+        Some((index, self.source.get(index)))
+    }
+}
+
+/// A thread's share of an arbitrary iterator, collected by split_iterators().
+pub struct VecPartition<S> {
+    items: vec::IntoIter<S>,
+    tid: usize,
+    num_threads: usize,
+    block_size: usize,
+    pos: usize,
+}
+
+impl<S> Iterator for VecPartition<S> {
+    type Item = (usize, S);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.items.next()?;
+        let block = self.pos / self.block_size;
+        let offset = self.pos % self.block_size;
+        self.pos += 1;
+        Some((
+            (block * self.num_threads + self.tid) * self.block_size + offset,
+            item,
+        ))
+    }
+}
+
+/// Wrapper selecting the splitting strategy for a par_for! iterator.
+///
+/// Calling split() on a &Splitter resolves to SplitIndexed for IndexedSource types and to
+/// SplitSequential for every other iterator.
+pub struct Splitter<T> {
+    source: Cell<Option<T>>,
+}
+
+impl<T> Splitter<T> {
+    pub fn new(source: T) -> Splitter<T> {
+        Splitter {
+            source: Cell::new(Some(source)),
+        }
+    }
+
+    fn take(&self) -> T {
+        self.source
+            .take()
+            .expect("Error: iteration source split twice")
+    }
+}
+
+/// Lazy splitting for IndexedSource types.
+pub trait SplitIndexed {
+    type Partition;
+
+    fn split(&self, team: &Team, block_size: usize) -> Vec<Self::Partition>;
+}
+
+impl<T: IndexedSource + Clone> SplitIndexed for Splitter<T> {
+    type Partition = IndexedPartition<T>;
+
+    fn split(&self, team: &Team, block_size: usize) -> Vec<IndexedPartition<T>> {
+        assert!(block_size > 0, "Error: blocksize must be at least 1");
+        indexed_partitions(self.take(), team.num_threads, block_size)
+    }
+}
+
+/// Splits source across num_threads partitions, see the module documentation.
+fn indexed_partitions<T: IndexedSource + Clone>(
+    source: T,
+    num_threads: usize,
+    block_size: usize,
+) -> Vec<IndexedPartition<T>> {
+    let len = source.len();
+    (0..num_threads)
+        .map(|tid| {
+            let block_start = tid.saturating_mul(block_size);
+            let block_end = min(block_start.saturating_add(block_size), len).max(block_start);
+            IndexedPartition {
+                source: source.clone(),
+                len,
+                block_size,
+                stride: num_threads.saturating_mul(block_size),
+                block_start,
+                block_end,
+                index: block_start,
+            }
+        })
+        .collect()
+}
+
+/// Fallback splitting for arbitrary iterators.
+pub trait SplitSequential {
+    type Partition;
+
+    fn split(&self, team: &Team, block_size: usize) -> Vec<Self::Partition>;
+}
+
+impl<T: Iterator> SplitSequential for &Splitter<T> {
+    type Partition = VecPartition<T::Item>;
+
+    fn split(&self, team: &Team, block_size: usize) -> Vec<VecPartition<T::Item>> {
+        assert!(block_size > 0, "Error: blocksize must be at least 1");
+        vec_partitions(team.split_iterators(self.take(), block_size), block_size)
+    }
+}
+
+/// Wraps the Vecs of split_iterators(), one per thread, as partitions.
+fn vec_partitions<S>(split: Vec<Vec<S>>, block_size: usize) -> Vec<VecPartition<S>> {
+    let num_threads = split.len();
+    split
+        .into_iter()
+        .enumerate()
+        .map(|(tid, items)| VecPartition {
+            items: items.into_iter(),
+            tid,
+            num_threads,
+            block_size,
+            pos: 0,
+        })
+        .collect()
+}
+
+/// Per-thread result buffers of a par_for! loop with a collect into clause.
+///
+/// Each thread submits the (global index, value) pairs it produced, and into_vec() stitches
+/// them back together block by block in iteration order.
+pub struct Collector<T> {
+    buffers: Vec<Mutex<Vec<(usize, T)>>>,
+    block_size: usize,
+}
+
+impl<T> Collector<T> {
+    pub fn new(num_threads: usize, block_size: usize) -> Collector<T> {
+        Collector {
+            buffers: (0..num_threads).map(|_| Mutex::new(Vec::new())).collect(),
+            block_size,
+        }
+    }
+
+    /// Stores the values produced by thread tid, in increasing index order.
+    pub fn submit(&self, tid: usize, buffer: Vec<(usize, T)>) {
+        if !buffer.is_empty() {
+            *self.buffers[tid].lock().unwrap() = buffer;
+        }
+    }
+
+    /// Returns all submitted values in iteration order.
+    ///
+    /// Iterations that did not produce a value (e.g. skipped by cancel()) are left out.
+    pub fn into_vec(self) -> Vec<T> {
+        let num_threads = self.buffers.len();
+        let mut buffers = self
+            .buffers
+            .into_iter()
+            .map(|buffer| buffer.into_inner().unwrap().into_iter().peekable())
+            .collect::<Vec<_>>();
+        let len = buffers.iter().map(|buffer| buffer.len()).sum();
+
+        let mut result = Vec::with_capacity(len);
+        let mut block = 0;
+        while result.len() < len {
+            let block_end = (block + 1) * self.block_size;
+            let buffer = &mut buffers[block % num_threads];
+            while let Some((_, value)) = buffer.next_if(|(index, _)| *index < block_end) {
+                result.push(value);
+            }
+            block += 1;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::threadpool::split_blocks;
+
+    fn indexed<T: IndexedSource + Clone>(
+        source: T,
+        num_threads: usize,
+        block_size: usize,
+    ) -> Vec<Vec<(usize, T::Item)>> {
+        indexed_partitions(source, num_threads, block_size)
+            .into_iter()
+            .map(Iterator::collect)
+            .collect()
+    }
+
+    fn sequential<T: Iterator>(
+        iter: T,
+        num_threads: usize,
+        block_size: usize,
+    ) -> Vec<Vec<(usize, T::Item)>> {
+        vec_partitions(split_blocks(iter, num_threads, block_size), block_size)
+            .into_iter()
+            .map(Iterator::collect)
+            .collect()
+    }
+
+    fn indices<S>(partition: &[(usize, S)]) -> Vec<usize> {
+        partition.iter().map(|(index, _)| *index).collect()
+    }
+
+    #[test]
+    fn indexed_deals_blocks_round_robin() {
+        let parts = indexed(10..20, 3, 2);
+        assert_eq!(indices(&parts[0]), [0, 1, 6, 7]);
+        assert_eq!(indices(&parts[1]), [2, 3, 8, 9]);
+        assert_eq!(indices(&parts[2]), [4, 5]);
+        for (index, item) in parts.iter().flatten() {
+            assert_eq!(*item, 10 + *index);
+        }
+    }
+
+    #[test]
+    fn indexed_block_size_not_dividing_len() {
+        let parts = indexed(0..7, 2, 3);
+        assert_eq!(indices(&parts[0]), [0, 1, 2, 6]);
+        assert_eq!(indices(&parts[1]), [3, 4, 5]);
+    }
+
+    #[test]
+    fn indexed_block_size_equal_to_len() {
+        let parts = indexed(0..4, 3, 4);
+        assert_eq!(indices(&parts[0]), [0, 1, 2, 3]);
+        assert!(parts[1].is_empty());
+        assert!(parts[2].is_empty());
+    }
+
+    #[test]
+    fn indexed_block_size_larger_than_len() {
+        let parts = indexed(0..5, 3, 8);
+        assert_eq!(indices(&parts[0]), [0, 1, 2, 3, 4]);
+        assert!(parts[1].is_empty());
+        assert!(parts[2].is_empty());
+
+        let parts = indexed(0..5, 2, usize::MAX);
+        assert_eq!(indices(&parts[0]), [0, 1, 2, 3, 4]);
+        assert!(parts[1].is_empty());
+    }
+
+    #[test]
+    fn indexed_empty_ranges() {
+        assert!(indexed(0..0, 4, 1).iter().all(Vec::is_empty));
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 5..3;
+        assert!(indexed(reversed, 4, 1).iter().all(Vec::is_empty));
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 5..=3;
+        assert!(indexed(reversed, 4, 1).iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn indexed_inclusive_ranges() {
+        let parts = indexed(1..=5, 2, 2);
+        assert_eq!(parts[0], [(0, 1), (1, 2), (4, 5)]);
+        assert_eq!(parts[1], [(2, 3), (3, 4)]);
+
+        let parts = indexed(-3i8..=3, 3, 1);
+        assert_eq!(parts[0], [(0, -3), (3, 0), (6, 3)]);
+
+        let parts = indexed(0u8..=255, 4, 16);
+        assert_eq!(parts.iter().map(Vec::len).sum::<usize>(), 256);
+        assert_eq!(parts[3].last(), Some(&(255, 255)));
+    }
+
+    #[test]
+    fn indexed_slices() {
+        let data = ["a", "b", "c", "d", "e"];
+        let parts = indexed(data.iter(), 2, 2);
+        assert_eq!(parts[0], [(0, &"a"), (1, &"b"), (4, &"e")]);
+        assert_eq!(parts[1], [(2, &"c"), (3, &"d")]);
+    }
+
+    #[test]
+    fn sequential_matches_indexed() {
+        for &(len, num_threads, block_size) in
+            &[(10, 3, 2), (7, 2, 3), (4, 3, 4), (5, 3, 8), (0, 2, 1)]
+        {
+            let parts = sequential((0..len).map(|i| i * 10), num_threads, block_size);
+            let expected = indexed(0..len, num_threads, block_size);
+            assert_eq!(parts.len(), num_threads);
+            for (part, expected) in parts.iter().zip(&expected) {
+                assert_eq!(indices(part), indices(expected));
+                for (index, item) in part {
+                    assert_eq!(*item, index * 10);
+                }
+            }
+        }
+    }
+
+    fn collect_all(num_threads: usize, block_size: usize, len: usize) -> Collector<usize> {
+        let collector = Collector::new(num_threads, block_size);
+        for (tid, part) in indexed(0..len, num_threads, block_size)
+            .into_iter()
+            .enumerate()
+        {
+            collector.submit(tid, part);
+        }
+        collector
+    }
+
+    #[test]
+    fn collector_restores_iteration_order() {
+        assert_eq!(
+            collect_all(3, 2, 10).into_vec(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert_eq!(collect_all(2, 3, 7).into_vec(), (0..7).collect::<Vec<_>>());
+        assert_eq!(collect_all(4, 8, 5).into_vec(), (0..5).collect::<Vec<_>>());
+        assert!(collect_all(2, 1, 0).into_vec().is_empty());
+    }
+
+    #[test]
+    fn collector_skips_cancelled_iterations() {
+        // Thread 1 is cancelled after its first block and thread 2 before starting
+        let collector = Collector::new(3, 2);
+        let mut parts = indexed(0..14, 3, 2);
+        parts[1].truncate(2);
+        parts[2].clear();
+        for (tid, part) in parts.into_iter().enumerate() {
+            collector.submit(tid, part);
+        }
+        assert_eq!(collector.into_vec(), [0, 1, 2, 3, 6, 7, 12, 13]);
+    }
+
+    #[test]
+    fn collector_skips_failed_iterations() {
+        // Thread 0 stops in the middle of its second block
+        let collector = Collector::new(2, 3);
+        let mut parts = indexed(0..12, 2, 3);
+        parts[0].truncate(4);
+        for (tid, part) in parts.into_iter().enumerate() {
+            collector.submit(tid, part);
+        }
+        assert_eq!(collector.into_vec(), [0, 1, 2, 3, 4, 5, 6, 9, 10, 11]);
+    }
+}
@@ -1,9 +1,13 @@
+use crate::config::{self, BindPolicy, PanicPolicy, WaitPolicy};
 use hwloc2::{CpuBindError, CpuBindFlags, ObjectType, Topology, TopologyObject};
 use lazy_static::lazy_static;
 use std::cmp::max;
 use std::env::var;
 use std::sync::Arc;
 
+/// Default name prefix of worker threads, followed by the worker's tid
+const DEFAULT_NAME_PREFIX: &str = "RMP_PAR_THREAD_#";
+
 lazy_static! {
     static ref INSTANCE: Arc<SystemObject> = Arc::new(SystemObject::new());
 }
@@ -11,8 +15,8 @@ lazy_static! {
 /// Represents a system object.
 ///
 /// The system object contains both hardware topology information as well as environment variable
-/// information used by RustMP. Interactions with the OS such as setting process affinity should be
-/// done through the system object.
+/// information used by RustMP, merged with the RmpConfig set before RustMP started. Interactions
+/// with the OS such as setting process affinity should be done through the system object.
 ///
 /// Only a single instance of SystemObject may exist at a time. Use get_instance() to get a
 /// thread-safe reference to the current SystemObject instance.
@@ -23,12 +27,30 @@ pub struct SystemObject {
     pub available_hwthreads: usize,
     /// Maximum number of threads to spawn for the RustMP thread pool
     pub max_num_threads: usize,
+    /// Maximum number of nested parallel regions running on their own team, deeper regions
+    /// run serially
+    pub max_active_levels: usize,
+    /// Stack size of worker threads in bytes
+    pub stack_size: usize,
+    /// Number of hwthreads (PUs) per core
+    pus_per_core: usize,
+    /// Number of hwthreads (PUs) per package
+    pus_per_package: usize,
+    /// Number of times an idle thread polls for work before yielding and then sleeping
+    pub spin_count: usize,
+    /// How worker threads are pinned by default
+    pub bind: BindPolicy,
+    /// Default name prefix of worker threads, followed by the worker's tid
+    pub name_prefix: String,
+    /// What happens when a task panics on a worker thread
+    pub panic_policy: PanicPolicy,
 }
 
 impl SystemObject {
     /// Instantiates a new SystemObject.
     ///
     /// Extra environment variables and hardware information used should be added here.
+    /// Environment variables that are set take precedence over the RmpConfig.
     ///
     /// SystemObject::new() should only be called by INSTANCE.
     fn new() -> SystemObject {
@@ -60,21 +82,81 @@ impl SystemObject {
                 let package_offset = x % puppa;
                 let core_id = package_offset % coppa;
                 let core_offset = package_offset / coppa;
-                return puppa * package_id + core_id * pupco + core_offset;
+                puppa * package_id + core_id * pupco + core_offset
             })
             .collect::<Vec<usize>>();
 
+        let config = config::take();
         let max_num_threads = max(
             var("RMP_NUM_THREADS")
-                .unwrap_or("".to_string())
-                .parse::<usize>()
+                .ok()
+                .and_then(|num_threads| num_threads.parse::<usize>().ok())
+                .or(config.num_threads)
                 .unwrap_or(available_hwthreads),
             1,
         );
+        // The outermost region always runs on its pool, so 0 behaves like 1
+        let max_active_levels = max(
+            var("RMP_MAX_ACTIVE_LEVELS")
+                .ok()
+                .and_then(|levels| levels.parse::<usize>().ok())
+                .or(config.max_active_levels)
+                .unwrap_or(1),
+            1,
+        );
+        // 8MB, the Linux default for the main thread
+        let stack_size = var("RMP_STACKSIZE")
+            .ok()
+            .and_then(|size| parse_size(&size))
+            .or(config.stack_size)
+            .unwrap_or(8 << 20);
+        let bind = match var("RMP_PROC_BIND")
+            .unwrap_or("".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "true" => BindPolicy::Cores,
+            "false" => BindPolicy::Unbound,
+            _ => config.bind.unwrap_or(BindPolicy::Cores),
+        };
+
+        // Mirrors OMP_WAIT_POLICY and GOMP_SPINCOUNT: passive threads sleep as soon as they
+        // are idle, active threads spin for RMP_SPINCOUNT polls (forever by default).
+        let spin_count = var("RMP_SPINCOUNT")
+            .ok()
+            .and_then(|count| match count.trim().to_lowercase().as_str() {
+                "infinite" | "infinity" => Some(usize::MAX),
+                count => count.parse::<usize>().ok(),
+            })
+            .or(config.spin_count);
+        let wait_policy = match var("RMP_WAIT_POLICY")
+            .unwrap_or("".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "passive" => Some(WaitPolicy::Passive),
+            "active" => Some(WaitPolicy::Active),
+            _ => config.wait_policy,
+        };
+        let spin_count = match wait_policy {
+            Some(WaitPolicy::Passive) => 0,
+            Some(WaitPolicy::Active) => spin_count.unwrap_or(usize::MAX),
+            None => spin_count.unwrap_or(0),
+        };
         SystemObject {
             cpu_bind_map,
             available_hwthreads,
             max_num_threads,
+            max_active_levels,
+            stack_size,
+            pus_per_core: pupco,
+            pus_per_package: puppa,
+            spin_count,
+            bind,
+            name_prefix: config
+                .name_prefix
+                .unwrap_or(DEFAULT_NAME_PREFIX.to_string()),
+            panic_policy: config.panic_policy.unwrap_or(PanicPolicy::Exit),
         }
     }
 
@@ -83,21 +165,51 @@ impl SystemObject {
         INSTANCE.clone()
     }
 
-    /// Binds the current thread to the corresponding hwthread specified in cpu_bind_map.
+    /// Returns the hwthread (PU) thread tid of a pool is bound to, following cpu_bind_map.
     ///
     /// Default binding rules are by core, then by hwthread on the same core, then by socket.
+    pub fn default_pu(&self, tid: usize) -> usize {
+        self.cpu_bind_map[tid % self.available_hwthreads]
+    }
+
+    /// Returns the package and the core (numbered across the machine) of the hwthread (PU)
+    /// with logical index pu.
+    pub fn pu_location(&self, pu: usize) -> (usize, usize) {
+        (pu / self.pus_per_package, pu / self.pus_per_core)
+    }
+
+    /// Binds the current thread to the hwthread (PU) with logical index pu.
     ///
     /// Returns an error if the process failed to bind
-    pub fn set_affinity(&self, tid: usize) -> Result<(), CpuBindError> {
+    pub fn bind_to_pu(&self, pu: usize) -> Result<(), CpuBindError> {
         let mut topo = Topology::new().unwrap();
         let pu_vec = topo.objects_with_type(&ObjectType::PU).unwrap();
-        let cpuset = pu_vec[self.cpu_bind_map[tid % self.available_hwthreads]]
-            .cpuset()
-            .unwrap();
+        let cpuset = pu_vec[pu].cpuset().unwrap();
         topo.set_cpubind(cpuset, CpuBindFlags::CPUBIND_THREAD)
     }
 }
 
+/// Parses a size in bytes written like OMP_STACKSIZE: a number followed by an optional B, K,
+/// M or G suffix (case insensitive), in kilobytes if there is none.
+///
+/// Returns None for anything else, or if the size overflows a usize, in which case
+/// RMP_STACKSIZE is ignored.
+fn parse_size(size: &str) -> Option<usize> {
+    let size = size.trim().to_uppercase();
+    let (number, shift) = match size.char_indices().last()? {
+        (i, 'B') => (&size[..i], 0),
+        (i, 'K') => (&size[..i], 10),
+        (i, 'M') => (&size[..i], 20),
+        (i, 'G') => (&size[..i], 30),
+        _ => (size.as_str(), 10),
+    };
+    number
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+}
+
 /// Recursively finds child topology objects of a defined type.
 ///
 /// Returns None if no children of the type is found.
@@ -109,18 +221,49 @@ fn children_with_type<'a>(
 
     for child in topo_obj.children() {
         if child.object_type() == *object_type {
-            objects.push(&child);
-        } else {
-            match children_with_type(child, object_type) {
-                Some(mut child_vec) => objects.append(&mut child_vec),
-                None => (),
-            }
+            objects.push(child);
+        } else if let Some(mut child_vec) = children_with_type(child, object_type) {
+            objects.append(&mut child_vec);
         }
     }
 
-    if objects.len() > 0 {
+    if !objects.is_empty() {
         Some(objects)
     } else {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("512B"), Some(512));
+        assert_eq!(parse_size("4K"), Some(4 << 10));
+        assert_eq!(parse_size("16m"), Some(16 << 20));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        assert_eq!(parse_size(" 8 M "), Some(8 << 20));
+    }
+
+    #[test]
+    fn parse_size_defaults_to_kilobytes() {
+        assert_eq!(parse_size("64"), Some(64 << 10));
+        assert_eq!(parse_size("0"), Some(0));
+    }
+
+    #[test]
+    fn parse_size_rejects_overflow() {
+        assert_eq!(parse_size(&format!("{}G", usize::MAX >> 29)), None);
+        assert_eq!(parse_size(&format!("{}", usize::MAX)), None);
+        assert_eq!(parse_size("99999999999999999999999B"), None);
+    }
+
+    #[test]
+    fn parse_size_rejects_garbage() {
+        for size in ["", " ", "K", "-1K", "1.5M", "12KB", "big", "1T"] {
+            assert_eq!(parse_size(size), None, "{:?}", size);
+        }
+    }
+}
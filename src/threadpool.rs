@@ -1,14 +1,48 @@
+use crate::barrier::{BarrierTree, Latch};
+use crate::config::{BindPolicy, PanicPolicy};
+use crate::dispatch::{channel, Receiver, Sender, TryRecv};
+use crate::runtime::{
+    current_region, enter_region, get_max_active_levels, get_max_threads, in_parallel,
+    set_max_threads, Region,
+};
 use crate::sysinfo::SystemObject;
 use lazy_static::lazy_static;
-use std::panic;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::hint::spin_loop;
+use std::mem::transmute;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
 use std::process;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Barrier, Mutex};
-use std::thread::{current, Builder, JoinHandle};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::task::{Context, Poll};
+use std::thread::{current, yield_now, Builder, JoinHandle};
 
 lazy_static! {
-    static ref INSTANCE: Arc<Mutex<ThreadPoolManager>> =
-        Arc::new(Mutex::new(ThreadPoolManager::new()));
+    static ref INSTANCE: ThreadPool = {
+        let pool = ThreadPoolBuilder::new().build();
+        set_max_threads(pool.num_threads());
+        pool
+    };
+}
+
+/// Source of ThreadPool ids
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Number of yield_now() rounds between spinning and sleeping, for threads that spin at all
+const YIELD_COUNT: usize = 64;
+
+thread_local! {
+    /// Id of the pool the current thread is a worker of, also used by the panic hook.
+    static WORKER_POOL: Cell<Option<usize>> = const { Cell::new(None) };
+    /// Pool running the nested regions started by the current worker, see ThreadPool::team().
+    static NESTED_POOL: RefCell<Option<ThreadPool>> = const { RefCell::new(None) };
+    /// Ids of the pools running the regions enclosing the current task, outermost first. The
+    /// workers of these pools are all busy until the task returns.
+    static ACTIVE_POOLS: RefCell<Option<Arc<[usize]>>> = const { RefCell::new(None) };
 }
 
 /// The Job type used to submit tasks for the ThreadPoolManager
@@ -27,16 +61,430 @@ where
     Arc::new(capture)
 }
 
+/// The job type used to submit borrowing tasks for the ThreadPoolManager.
+///
+/// Unlike a Job, a ScopedJob may borrow from the stack of the thread submitting it, see
+/// exec_scoped(). Each ScopedJob is run exactly once.
+pub type ScopedJob<'a> = Box<dyn FnOnce() + Send + 'a>;
+
+/// Converts a function capture into a ScopedJob borrowing from the current scope.
+pub fn as_scoped_job<'a, T>(capture: T) -> ScopedJob<'a>
+where
+    T: FnOnce() + Send + 'a,
+{
+    Box::new(capture)
+}
+
+/// Configuration of a new ThreadPool.
+///
+/// Pools built with the default configuration behave like the global pool: RMP_NUM_THREADS
+/// threads, pinned following SystemObject's binding rules, or as set by RmpConfig.
+pub struct ThreadPoolBuilder {
+    num_threads: Option<usize>,
+    bind: Option<BindPolicy>,
+    name_prefix: Option<String>,
+    stack_size: Option<usize>,
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            num_threads: None,
+            bind: None,
+            name_prefix: None,
+            stack_size: None,
+        }
+    }
+
+    /// Sets the number of worker threads, RMP_NUM_THREADS by default.
+    pub fn num_threads(mut self, num_threads: usize) -> ThreadPoolBuilder {
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Pins worker tid to the hwthread (PU) with logical index cpus[tid % cpus.len()], the
+    /// same as bind(BindPolicy::Cpus(cpus)).
+    pub fn cpus(self, cpus: Vec<usize>) -> ThreadPoolBuilder {
+        self.bind(BindPolicy::Cpus(cpus))
+    }
+
+    /// Sets how worker threads are pinned, RMP_PROC_BIND by default.
+    pub fn bind(mut self, bind: BindPolicy) -> ThreadPoolBuilder {
+        self.bind = Some(bind);
+        self
+    }
+
+    /// Sets the name of worker tid to the prefix followed by tid.
+    pub fn name_prefix(mut self, name_prefix: &str) -> ThreadPoolBuilder {
+        self.name_prefix = Some(name_prefix.to_string());
+        self
+    }
+
+    /// Sets the stack size of worker threads in bytes, RMP_STACKSIZE by default.
+    pub fn stack_size(mut self, stack_size: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Spawns the worker threads.
+    ///
+    /// Panics if the pool would have no threads, or if cpus is empty or names a hwthread that
+    /// does not exist.
+    pub fn build(self) -> ThreadPool {
+        let id = NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed);
+        ThreadPool {
+            id,
+            manager: Arc::new(Mutex::new(ThreadPoolManager::new(id, self))),
+        }
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle to a pool of pinned worker threads.
+///
+/// par_for! runs on the global pool unless given a `pool` clause. Separate pools run their
+/// loops independently of each other, while loops started concurrently on the same pool share
+/// its workers, see ThreadPoolManager. Cloned handles refer to the same pool.
+#[derive(Clone)]
+pub struct ThreadPool {
+    id: usize,
+    manager: Arc<Mutex<ThreadPoolManager>>,
+}
+
+impl ThreadPool {
+    /// Creates a pool of num_threads threads, see ThreadPoolBuilder for other options.
+    pub fn new(num_threads: usize) -> ThreadPool {
+        ThreadPoolBuilder::new().num_threads(num_threads).build()
+    }
+
+    /// Returns a handle to the global pool used by default.
+    pub fn global() -> ThreadPool {
+        INSTANCE.clone()
+    }
+
+    /// Returns the number of threads loops on this pool run on.
+    pub fn num_threads(&self) -> usize {
+        self.manager.lock().unwrap().num_threads
+    }
+
+    /// Stops the workers of the pool and waits for them to exit, see
+    /// ThreadPoolManager::shutdown().
+    pub fn shutdown(&self) {
+        // Joined outside of the lock, so the workers can still take teams from the pool
+        let workers = self.manager.lock().unwrap().stop();
+        drop(workers);
+    }
+
+    /// Resizes the pool, see ThreadPoolManager::set_num_threads().
+    pub fn set_num_threads(&self, num_threads: usize) {
+        self.manager.lock().unwrap().set_num_threads(num_threads);
+        if self.id == INSTANCE.id {
+            set_max_threads(num_threads);
+        }
+    }
+
+    /// Starts a set of num_threads() tasks on the pool without waiting for them, see
+    /// ThreadPoolManager::spawn_region().
+    pub fn spawn_region(&self, tasks: Vec<Job>) -> RegionHandle {
+        self.team().spawn_region(tasks)
+    }
+
+    /// Gets the ThreadPoolManager of this pool, the same way get_instance_guard() does for
+    /// the global pool.
+    pub fn manager(&self) -> Arc<Mutex<ThreadPoolManager>> {
+        self.manager.clone()
+    }
+
+    /// Gets the team of workers a parallel region started on this pool by the calling thread
+    /// should run on.
+    ///
+    /// Outside of a parallel region this is the pool itself. Inside of one, the region is
+    /// nested: once RMP_MAX_ACTIVE_LEVELS regions are active it runs serially on the calling
+    /// thread. Otherwise, a region started on a pool whose workers are busy with one of the
+    /// enclosing regions, at any level, gets a team from a nested pool owned by the calling
+    /// worker. The nested pool is created with get_max_threads() unbound threads on first use
+    /// and kept for later nested regions.
+    pub fn team(&self) -> Team {
+        nested_team(self.id).unwrap_or_else(|| self.manager.lock().unwrap().workers_team())
+    }
+}
+
+/// Returns the team of a region started on pool inside of another region, see
+/// ThreadPool::team(), or None if the region can run on the workers of pool.
+fn nested_team(pool: usize) -> Option<Team> {
+    let level = current_region().level;
+    if level == 0 {
+        None
+    } else if level >= get_max_active_levels() {
+        Some(Team::serial())
+    } else if active_pools(|active| active.contains(&pool)) {
+        Some(NESTED_POOL.with(|nested| {
+            nested
+                .borrow_mut()
+                .get_or_insert_with(|| {
+                    // Unbound, so the nested workers are not pinned to the same hwthreads as
+                    // the workers of the enclosing region
+                    ThreadPoolBuilder::new()
+                        .num_threads(get_max_threads())
+                        .bind(BindPolicy::Unbound)
+                        .name_prefix(&format!("{}.", current().name().unwrap_or_default()))
+                        .build()
+                })
+                .team()
+        }))
+    } else {
+        None
+    }
+}
+
+/// Runs f with the ids of the pools running the regions enclosing the current task.
+fn active_pools<R>(f: impl FnOnce(&[usize]) -> R) -> R {
+    ACTIVE_POOLS.with(|active| f(active.borrow().as_deref().unwrap_or_default()))
+}
+
+/// A worker thread of a pool, shared by the pool, the Teams taken from it and the tasks queued
+/// on the worker.
+///
+/// Dropping the last reference closes the worker's queue, so that it exits, and waits for it
+/// to exit. A pool dropped or shut down while one of its Teams is alive, or one of its regions
+/// is running, keeps its workers until they are released, instead of waiting for queues that
+/// cannot close yet.
+struct Worker {
+    /// Only None while dropping
+    sender: Option<Sender<ScopedJob<'static>>>,
+    /// Hwthread (PU) the worker is bound to, None if unbound
+    pu: Option<usize>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn send(&self, task: ScopedJob<'static>) {
+        self.sender.as_ref().unwrap().send(task);
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // The worker holds the only Sender of its queue, dropping it closes the queue
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            // When the last reference is dropped by the worker itself, once its last task
+            // returns, it exits on its own
+            if handle.thread().id() != current().id() {
+                // A panicking worker exits the process, so join() cannot return an error
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+/// Workers running one parallel region.
+///
+/// A Team is a snapshot of its pool taken when the region starts, so the pool is not locked
+/// while the region runs and later resizes do not affect the region. The workers of the team
+/// stay alive as long as the team, see Worker.
+pub struct Team {
+    pub num_threads: usize,
+    /// Id of the pool the workers belong to, None for serial teams
+    pool: Option<usize>,
+    submit_lock: Arc<Mutex<()>>,
+    workers: Vec<Arc<Worker>>,
+    tree: Arc<BarrierTree>,
+}
+
+impl Team {
+    /// Creates a Team without workers, running its single task on the calling thread. Used
+    /// for parallel regions that are nested too deeply to be active.
+    fn serial() -> Team {
+        Team {
+            num_threads: 1,
+            pool: None,
+            submit_lock: Arc::new(Mutex::new(())),
+            workers: Vec::new(),
+            tree: Arc::new(BarrierTree::flat(1)),
+        }
+    }
+
+    /// Execute a set of tasks on the team, see ThreadPoolManager::exec().
+    pub fn exec(&self, tasks: Vec<Job>) {
+        self.exec_scoped(
+            tasks
+                .into_iter()
+                .map(|task| as_scoped_job(move || task()))
+                .collect(),
+        );
+    }
+
+    /// Execute a set of tasks borrowing from the caller's scope on the team, see
+    /// ThreadPoolManager::exec_scoped().
+    pub fn exec_scoped<'a>(&self, tasks: Vec<ScopedJob<'a>>) {
+        // Safety: the latch is waited on before returning, and a panicking task either exits the
+        // process or is caught before its worker counts down the latch, so nothing borrowed by
+        // the tasks can be accessed after this function returns.
+        let latch = unsafe { self.submit(tasks) };
+        // Used to return main thread from exec
+        latch.wait();
+    }
+
+    /// Starts a set of tasks on the team without waiting for them, see
+    /// ThreadPoolManager::spawn_region().
+    pub fn spawn_region(&self, tasks: Vec<Job>) -> RegionHandle {
+        let tasks = tasks
+            .into_iter()
+            .map(|task| as_scoped_job(move || task()))
+            .collect();
+        // Safety: the tasks are 'static
+        RegionHandle {
+            latch: unsafe { self.submit(tasks) },
+        }
+    }
+
+    /// Queues one task on each worker of the team, and returns the latch counting down the
+    /// tasks that have not finished yet. A serial team runs its task before returning.
+    ///
+    /// # Safety
+    ///
+    /// Anything borrowed by the tasks must outlive the latch reaching 0.
+    unsafe fn submit<'a>(&self, tasks: Vec<ScopedJob<'a>>) -> Arc<Latch> {
+        assert_eq!(self.num_threads, tasks.len());
+        let num_threads = self.num_threads;
+        let level = current_region().level + 1;
+        let latch = Arc::new(Latch::new(self.tree.clone()));
+        if self.workers.is_empty() {
+            let _region = enter_region(Region {
+                thread_num: 0,
+                num_threads,
+                level,
+            });
+            for task in tasks {
+                task();
+                latch.count_down(0);
+            }
+            return latch;
+        }
+        // Read by regions nested in the tasks, see ThreadPool::team()
+        let pools: Arc<[usize]> =
+            active_pools(|active| active.iter().copied().chain(self.pool).collect());
+        // Queue the whole region at once, so concurrent regions run in the same order on every
+        // worker
+        let _submit = self.submit_lock.lock().unwrap();
+        for (thread_num, (worker, task)) in self.workers.iter().zip(tasks).enumerate() {
+            let latch = latch.clone();
+            // Keeps the worker alive until the task has run, even if the team is dropped
+            // before, e.g. by spawn_region() on a pool that is being dropped
+            let owner = worker.clone();
+            let pools = pools.clone();
+            let task = as_scoped_job(move || {
+                let outer = ACTIVE_POOLS.with(|active| active.replace(Some(pools)));
+                {
+                    let _region = enter_region(Region {
+                        thread_num,
+                        num_threads,
+                        level,
+                    });
+                    // With PanicPolicy::Propagate the panic hook lets the worker unwind, and
+                    // the panic is resumed by the thread waiting for the region instead
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(task)) {
+                        latch.set_panic(payload);
+                    }
+                }
+                ACTIVE_POOLS.with(|active| active.replace(outer));
+                // Released before the region completes, so that a pool shut down right after
+                // the region is the last owner of the worker and joins it
+                drop(owner);
+                latch.count_down(thread_num);
+            });
+            // The task is run and dropped by its worker before the worker counts down the
+            // latch, which the caller guarantees is enough for the borrows to stay valid.
+            let task = transmute::<ScopedJob<'a>, ScopedJob<'static>>(task);
+            worker.send(task);
+        }
+        latch
+    }
+
+    /// Splits an iterator between the threads of the team, see
+    /// ThreadPoolManager::split_iterators().
+    pub fn split_iterators<T, S>(&self, iter: T, block_size: usize) -> Vec<Vec<S>>
+    where
+        T: Iterator<Item = S>,
+    {
+        split_blocks(iter, self.num_threads, block_size)
+    }
+}
+
+/// Handle to a region started by spawn_region().
+///
+/// The region runs to completion whether or not the handle is kept. join() waits for it on the
+/// calling thread, and the handle is also a Future completing with the region, for callers
+/// running on an async executor.
+pub struct RegionHandle {
+    latch: Arc<Latch>,
+}
+
+impl RegionHandle {
+    /// Waits for every task of the region to finish.
+    ///
+    /// With PanicPolicy::Propagate, resumes the panic of a task that panicked, if any.
+    pub fn join(self) {
+        self.latch.wait();
+    }
+
+    /// Returns true once every task of the region has finished.
+    pub fn is_finished(&self) -> bool {
+        self.latch.is_done()
+    }
+}
+
+impl Future for RegionHandle {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.latch.poll(cx)
+    }
+}
+
+/// Polls ready spin_count times, then YIELD_COUNT more times yielding the thread in between,
+/// following RMP_WAIT_POLICY and RMP_SPINCOUNT. Returns None if the caller should sleep.
+pub(crate) fn spin_wait<T>(spin_count: usize, mut ready: impl FnMut() -> Option<T>) -> Option<T> {
+    if spin_count == 0 {
+        return None;
+    }
+    for _ in 0..spin_count {
+        if let Some(value) = ready() {
+            return Some(value);
+        }
+        spin_loop();
+    }
+    for _ in 0..YIELD_COUNT {
+        if let Some(value) = ready() {
+            return Some(value);
+        }
+        yield_now();
+    }
+    ready()
+}
+
 /// The ThreadPoolManager handles dispatching threads and sending Jobs to threads.
 ///
-/// Only one thread can submit and execute Jobs to the ThreadPoolManager instance at a time.
-/// Other threads attempting to lock the manager would wait on the ThreadPoolManager until
-/// the last thread using it unlocks the instance.
+/// Several threads can execute Jobs on the same ThreadPoolManager at a time: each worker runs
+/// its tasks in the order the regions were submitted, so a region submitted while another one
+/// runs is queued behind it. The manager only needs to stay locked while taking a team() for a
+/// region, not while the region runs.
 pub struct ThreadPoolManager {
     pub num_threads: usize,
-    task_barrier: Arc<Barrier>,
-    task_comms: Vec<Sender<Job>>,
-    _thread_pool: Vec<JoinHandle<()>>,
+    id: usize,
+    bind: BindPolicy,
+    name_prefix: String,
+    stack_size: usize,
+    submit_lock: Arc<Mutex<()>>,
+    workers: Vec<Arc<Worker>>,
+    /// Completion tree of the current team, rebuilt when resizing
+    tree: Arc<BarrierTree>,
 }
 
 impl ThreadPoolManager {
@@ -44,48 +492,126 @@ impl ThreadPoolManager {
     ///
     /// To get the current ThreadPoolManager, use get_instance_guard() instead.
     ///
-    /// Should only be called by the INSTANCE.
-    fn new() -> ThreadPoolManager {
-        let master_hook = panic::take_hook();
-        // Crash the program if any of our threads panic
-        panic::set_hook(Box::new(move |info| {
-            // Only panic on our own threads, leave application programmer's threads alone
-            if current()
-                .name()
-                .unwrap_or_default()
-                .starts_with("RMP_PAR_THREAD_#")
-            {
-                master_hook(info);
-                process::exit(1);
-            } else {
+    /// Should only be called by ThreadPoolBuilder.
+    fn new(id: usize, config: ThreadPoolBuilder) -> ThreadPoolManager {
+        PANIC_HOOK.call_once(|| {
+            let master_hook = panic::take_hook();
+            // Crash the program if any of our threads panic, unless the panic is propagated
+            panic::set_hook(Box::new(move |info| {
                 master_hook(info);
+                // Only exit on our own threads, leave application programmer's threads alone
+                if WORKER_POOL.with(|pool| pool.get()).is_some() {
+                    match SystemObject::get_instance().panic_policy {
+                        PanicPolicy::Exit => process::exit(1),
+                        PanicPolicy::Abort => process::abort(),
+                        PanicPolicy::Propagate => {}
+                    }
+                }
+            }));
+        });
+
+        let system = SystemObject::get_instance();
+        let bind = config.bind.unwrap_or_else(|| system.bind.clone());
+        if let BindPolicy::Cpus(cpus) = &bind {
+            assert!(!cpus.is_empty(), "Error: empty cpu set for thread pool");
+            if let Some(cpu) = cpus.iter().find(|&&cpu| cpu >= system.available_hwthreads) {
+                panic!(
+                    "Error: hwthread {} does not exist, the machine has {} hwthreads",
+                    cpu, system.available_hwthreads
+                );
             }
-        }));
+        }
 
-        let num_threads = SystemObject::get_instance().max_num_threads;
-        let task_barrier = Arc::new(Barrier::new(num_threads + 1));
-        let mut _thread_pool = Vec::new();
-        let mut task_comms = Vec::new();
+        let mut tpm = ThreadPoolManager {
+            num_threads: 0,
+            id,
+            bind,
+            name_prefix: config
+                .name_prefix
+                .unwrap_or_else(|| system.name_prefix.clone()),
+            stack_size: config.stack_size.unwrap_or(system.stack_size),
+            submit_lock: Arc::new(Mutex::new(())),
+            workers: Vec::new(),
+            tree: Arc::new(BarrierTree::flat(0)),
+        };
+        tpm.set_num_threads(config.num_threads.unwrap_or(system.max_num_threads));
+        tpm
+    }
 
-        for tid in 0..num_threads {
-            let task_barrier = task_barrier.clone();
+    /// Sets the number of threads used by subsequent exec() and exec_scoped() calls.
+    ///
+    /// Regions that already took their team() keep running on the old number of threads. New
+    /// workers are spawned and pinned when growing. When shrinking, the queues of the extra
+    /// workers are closed, so they exit once the regions already queued on them are done
+    /// instead of polling for work that will never come under RMP_WAIT_POLICY=active.
+    pub fn set_num_threads(&mut self, num_threads: usize) {
+        assert!(
+            num_threads > 0,
+            "Error: the thread pool needs at least one thread"
+        );
+        self.workers.truncate(num_threads);
+        let system = SystemObject::get_instance();
+        for tid in self.workers.len()..num_threads {
             let builder = Builder::new() // Thread builder configuration
-                .name(format!("RMP_PAR_THREAD_#{}", tid)) // Name: RMP_PAR_THREAD_tid
-                .stack_size(8 << 20); // Stack size: 8MB (Linux default)
-            let (sender, receiver) = channel::<Job>();
-            task_comms.push(sender);
-            _thread_pool.push(
-                builder
-                    .spawn(move || routine_wrapper(tid, task_barrier, receiver))
-                    .unwrap(),
-            );
+                .name(format!("{}{}", self.name_prefix, tid)) // Name: prefix followed by tid
+                .stack_size(self.stack_size); // Stack size: RMP_STACKSIZE, 8MB by default
+            let pu = match &self.bind {
+                BindPolicy::Cores => Some(system.default_pu(tid)),
+                BindPolicy::Cpus(cpus) => Some(cpus[tid % cpus.len()]),
+                BindPolicy::Unbound => None,
+            };
+            let id = self.id;
+            let (sender, receiver) = channel::<ScopedJob<'static>>();
+            let handle = builder
+                .spawn(move || routine_wrapper(id, tid, pu, receiver))
+                .unwrap();
+            self.workers.push(Arc::new(Worker {
+                sender: Some(sender),
+                pu,
+                handle: Some(handle),
+            }));
         }
+        if num_threads != self.num_threads {
+            // Unbound workers can run anywhere, so they all count down the root
+            let pus = self
+                .workers
+                .iter()
+                .map(|worker| worker.pu)
+                .collect::<Option<Vec<_>>>();
+            self.tree = Arc::new(match pus {
+                Some(pus) => BarrierTree::new(&pus),
+                None => BarrierTree::flat(num_threads),
+            });
+        }
+        self.num_threads = num_threads;
+    }
 
-        ThreadPoolManager {
-            num_threads,
-            task_barrier,
-            task_comms,
-            _thread_pool,
+    /// Returns the workers the next region on this pool runs on, spawning them again if the
+    /// pool has been shut down.
+    ///
+    /// Panics if called from inside a parallel region: the tasks of a nested region would be
+    /// queued behind the enclosing region on workers that may be waiting for it. Nested regions
+    /// are started through ThreadPool::team() instead, which runs them serially or on a nested
+    /// team.
+    pub fn team(&mut self) -> Team {
+        assert!(
+            !in_parallel(),
+            "Error: ThreadPoolManager regions cannot be nested, use ThreadPool::team() instead"
+        );
+        self.workers_team()
+    }
+
+    /// Returns the workers the next region on this pool runs on, see team().
+    fn workers_team(&mut self) -> Team {
+        if self.workers.is_empty() {
+            self.set_num_threads(self.num_threads);
+        }
+        Team {
+            num_threads: self.num_threads,
+            pool: Some(self.id),
+            submit_lock: self.submit_lock.clone(),
+            workers: self.workers.clone(),
+            tree: self.tree.clone(),
         }
     }
 
@@ -94,60 +620,125 @@ impl ThreadPoolManager {
     /// The instance needs to be locked before using, not unlocking the TPM after use
     /// may result in deadlock.
     pub fn get_instance_guard() -> Arc<Mutex<ThreadPoolManager>> {
-        return INSTANCE.clone();
+        INSTANCE.manager()
     }
 
     /// Execute a set of tasks on the ThreadPoolManager.
     ///
     /// The task vector must be the same size as the number of threads, otherwise a panic will
-    /// be thrown.
-    pub fn exec(&self, tasks: Vec<Job>) {
-        // Used to wake up threads
-        self.task_barrier.wait();
-        assert_eq!(self.num_threads, tasks.len());
-        for i in 0..tasks.len() {
-            self.task_comms[i].send(tasks[i].clone()).unwrap();
-        }
-        // Used to return main thread from exec
-        self.task_barrier.wait();
+    /// be thrown. Panics inside a parallel region, see team().
+    pub fn exec(&mut self, tasks: Vec<Job>) {
+        self.team().exec(tasks);
+    }
+
+    /// Starts a set of tasks on the ThreadPoolManager and returns without waiting for them.
+    ///
+    /// The returned RegionHandle can be joined or awaited later, so the calling thread can do
+    /// other work, such as I/O, while the region runs.
+    ///
+    /// The task vector must be the same size as the number of threads, otherwise a panic will
+    /// be thrown. Panics inside a parallel region, see team().
+    pub fn spawn_region(&mut self, tasks: Vec<Job>) -> RegionHandle {
+        self.team().spawn_region(tasks)
+    }
+
+    /// Execute a set of tasks borrowing from the caller's scope on the ThreadPoolManager.
+    ///
+    /// Works like std::thread::scope(): the call only returns once every task has finished,
+    /// so tasks may hold references to data on the caller's stack instead of 'static clones.
+    ///
+    /// The task vector must be the same size as the number of threads, otherwise a panic will
+    /// be thrown. Panics inside a parallel region, see team().
+    pub fn exec_scoped(&mut self, tasks: Vec<ScopedJob<'_>>) {
+        self.team().exec_scoped(tasks);
     }
 
     /// Splits an iterator into RMP_NUM_THREADS iterators, each with a step size of
     /// block_size.
     ///
-    /// Returned iterators are stored in a Vec<Vec<S>>, but anything should work as
-    /// long as the default Rust for loop accepts it.
+    /// Returned iterators are stored in a Vec<Vec<S>>. Blocks assigned to a thread are
+    /// stored back to back, so chunks(block_size) yields that thread's blocks in order.
+    ///
+    /// This drains the whole iterator up front. par_for! only uses it as a fallback for
+    /// iterators that cannot be split lazily, see the split module.
     pub fn split_iterators<T, S>(&self, iter: T, block_size: usize) -> Vec<Vec<S>>
     where
         T: Iterator<Item = S>,
     {
-        let mut split = Vec::with_capacity(self.num_threads);
-        for _ in 0..self.num_threads {
-            split.push(Vec::new());
-        }
-
-        let mut index: usize = 0;
-        let mut block: usize = 0;
-        for element in iter {
-            split[index].push(element);
-            block += 1;
-            if block % block_size == 0 {
-                block = 0;
-                index = (index + 1) % self.num_threads;
-            }
+        split_blocks(iter, self.num_threads, block_size)
+    }
+
+    /// Signals the workers to exit once they have run the tasks already queued, and waits
+    /// for them to exit.
+    ///
+    /// Teams taken from the pool keep their workers running until they are dropped, and the
+    /// workers are joined by whichever drops them last. A worker shutting down its own pool is
+    /// not waited for, it exits once its current task returns.
+    ///
+    /// The next region started on the pool, through either the manager or a ThreadPool handle,
+    /// spawns num_threads workers again.
+    pub fn shutdown(&mut self) {
+        drop(self.stop());
+    }
+
+    /// Releases the workers, keeping num_threads for the next team(). The workers exit once the
+    /// returned references and those of the pool's Teams are dropped, see Worker.
+    fn stop(&mut self) -> Vec<Arc<Worker>> {
+        std::mem::take(&mut self.workers)
+    }
+}
+
+/// Deals the elements of iter out to num_threads Vecs, block_size elements at a time.
+pub(crate) fn split_blocks<T, S>(iter: T, num_threads: usize, block_size: usize) -> Vec<Vec<S>>
+where
+    T: Iterator<Item = S>,
+{
+    let mut split = Vec::with_capacity(num_threads);
+    for _ in 0..num_threads {
+        split.push(Vec::new());
+    }
+
+    let mut index: usize = 0;
+    let mut block: usize = 0;
+    for element in iter {
+        split[index].push(element);
+        block += 1;
+        if block == block_size {
+            block = 0;
+            index = (index + 1) % num_threads;
         }
-        split
     }
+    split
 }
 
 /// Wrapper routine for threads in the ThreadPoolManager
-fn routine_wrapper(tid: usize, task_barrier: Arc<Barrier>, receiver: Receiver<Job>) {
-    SystemObject::get_instance()
-        .set_affinity(tid)
-        .unwrap_or_else(|e| eprintln!("Failed to bind process #{} to hwthread: {:?}", tid, e));
+///
+/// Binds the thread to the hwthread pu, if any. Idle workers poll their queue as set by
+/// RMP_WAIT_POLICY, then sleep on it until the next task arrives.
+fn routine_wrapper(
+    pool: usize,
+    tid: usize,
+    pu: Option<usize>,
+    mut receiver: Receiver<ScopedJob<'static>>,
+) {
+    WORKER_POOL.with(|worker_pool| worker_pool.set(Some(pool)));
+    let system = SystemObject::get_instance();
+    if let Some(pu) = pu {
+        system
+            .bind_to_pu(pu)
+            .unwrap_or_else(|e| eprintln!("Failed to bind process #{} to hwthread: {:?}", tid, e));
+    }
+    let spin_count = system.spin_count;
     loop {
-        task_barrier.wait();
-        receiver.recv().unwrap()();
-        task_barrier.wait();
+        let next = spin_wait(spin_count, || match receiver.try_recv() {
+            TryRecv::Task(task) => Some(Some(task)),
+            TryRecv::Empty => None,
+            TryRecv::Closed => Some(None),
+        });
+        // Sleep on the queue once done spinning, until the next task or the pool is dropped
+        match next.unwrap_or_else(|| receiver.recv()) {
+            Some(task) => task(),
+            None => break,
+        }
     }
 }
@@ -0,0 +1,7 @@
+//! Clause errors reported at compile time by the parallel! front end and par_map!.
+
+#[test]
+fn clause_errors() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,32 @@
+//! copyin with parallel regions started concurrently from several threads.
+
+use rustmp::{par_for, threadprivate};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+threadprivate! {
+    static VALUE: usize = 0;
+}
+
+#[test]
+fn concurrent_regions_copy_in_their_own_value() {
+    let mismatches = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for value in 1..=4 {
+            let mismatches = &mismatches;
+            s.spawn(move || {
+                for _ in 0..50 {
+                    VALUE.set(value);
+                    par_for! {
+                        for _i in 0..16, copyin VALUE, {
+                            if VALUE.get() != value {
+                                mismatches.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(mismatches.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,70 @@
+//! par_for! nested three levels deep, serially and on nested teams.
+
+use rustmp::{get_level, get_num_threads, par_for, set_max_active_levels};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Serializes the tests, which change the global max active levels
+static LEVELS: Mutex<()> = Mutex::new(());
+
+/// Runs three nested loops of n iterations each, and returns the number of innermost iterations
+/// and the largest number of threads seen at each level.
+fn nested_loops(n: usize) -> (usize, [usize; 3]) {
+    let count = AtomicUsize::new(0);
+    let threads = [
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+    ];
+    par_for! {
+        for _i in 0..n, {
+            threads[0].fetch_max(get_num_threads(), Ordering::Relaxed);
+            par_for! {
+                for _j in 0..n, {
+                    threads[1].fetch_max(get_num_threads(), Ordering::Relaxed);
+                    par_for! {
+                        for _k in 0..n, {
+                            assert_eq!(get_level(), 3);
+                            threads[2].fetch_max(get_num_threads(), Ordering::Relaxed);
+                            count.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let threads = threads.map(|threads| threads.into_inner());
+    (count.into_inner(), threads)
+}
+
+#[test]
+fn nested_regions_run_serially_by_default() {
+    let _levels = LEVELS.lock().unwrap();
+    set_max_active_levels(1);
+    let (count, threads) = nested_loops(8);
+    assert_eq!(count, 8 * 8 * 8);
+    assert_eq!(threads[1], 1);
+    assert_eq!(threads[2], 1);
+}
+
+#[test]
+fn three_active_levels() {
+    let _levels = LEVELS.lock().unwrap();
+    set_max_active_levels(3);
+    let (count, threads) = nested_loops(8);
+    set_max_active_levels(1);
+    assert_eq!(count, 8 * 8 * 8);
+    assert_eq!(threads[1], threads[0]);
+    assert_eq!(threads[2], threads[0]);
+}
+
+#[test]
+fn levels_past_the_limit_run_serially() {
+    let _levels = LEVELS.lock().unwrap();
+    set_max_active_levels(2);
+    let (count, threads) = nested_loops(8);
+    set_max_active_levels(1);
+    assert_eq!(count, 8 * 8 * 8);
+    assert_eq!(threads[1], threads[0]);
+    assert_eq!(threads[2], 1);
+}
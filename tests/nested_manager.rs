@@ -0,0 +1,23 @@
+//! Regions started on a ThreadPoolManager from inside a parallel region.
+
+use rustmp::{as_static_job, par_for, PanicPolicy, RmpConfig, ThreadPoolManager};
+
+#[test]
+#[should_panic(expected = "ThreadPoolManager regions cannot be nested")]
+fn nested_manager_region_panics_instead_of_hanging() {
+    // Resumes the worker's panic on this thread instead of exiting the process
+    RmpConfig::builder()
+        .panic_policy(PanicPolicy::Propagate)
+        .build()
+        .init()
+        .unwrap();
+    par_for! {
+        for _i in 0..4, {
+            let tpm = ThreadPoolManager::get_instance_guard();
+            // The first worker to panic poisons the lock, the others panic the same way
+            let mut tpm = tpm.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let tasks = (0..tpm.num_threads).map(|_| as_static_job(|| {})).collect();
+            tpm.exec(tasks);
+        }
+    }
+}
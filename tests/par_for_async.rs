@@ -0,0 +1,89 @@
+//! Awaiting par_for_async() loops from a minimal single threaded executor.
+
+use rustmp::{cancel, par_for_async};
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{current, park_timeout, sleep, Thread};
+use std::time::{Duration, Instant};
+
+/// Waker unparking the thread blocked on the future.
+struct ThreadWaker {
+    thread: Thread,
+    woken: AtomicBool,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.thread.unpark();
+    }
+}
+
+/// Polls future until it is ready, only polling again once woken. Calls on_pending after every
+/// Pending, and returns the output and the number of Pending polls.
+fn block_on<F: Future>(future: F, mut on_pending: impl FnMut()) -> (F::Output, usize) {
+    let state = Arc::new(ThreadWaker {
+        thread: current(),
+        woken: AtomicBool::new(false),
+    });
+    let waker = Waker::from(state.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    let mut pending = 0;
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return (output, pending);
+        }
+        pending += 1;
+        on_pending();
+        let start = Instant::now();
+        while !state.woken.swap(false, Ordering::Acquire) {
+            assert!(start.elapsed() < Duration::from_secs(10), "never woken");
+            park_timeout(Duration::from_millis(100));
+        }
+    }
+}
+
+#[test]
+fn wakes_the_task_awaiting_the_loop() {
+    let started = Arc::new(AtomicBool::new(false));
+    let count = Arc::new(AtomicUsize::new(0));
+    let handle = {
+        let (started, count) = (started.clone(), count.clone());
+        par_for_async(0..100, move |_| {
+            // Holds the loop until the handle has been polled once
+            while !started.load(Ordering::Acquire) {
+                sleep(Duration::from_millis(1));
+            }
+            count.fetch_add(1, Ordering::Relaxed);
+        })
+    };
+    let ((), pending) = block_on(handle, || started.store(true, Ordering::Release));
+    assert!(pending >= 1);
+    assert_eq!(count.load(Ordering::Relaxed), 100);
+}
+
+#[test]
+fn cancel_inside_the_async_body() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let handle = {
+        let count = count.clone();
+        par_for_async(0..100_000, move |i| {
+            if i == 10 {
+                cancel();
+            }
+            count.fetch_add(1, Ordering::Relaxed);
+        })
+    };
+    block_on(handle, || {});
+    let count = count.load(Ordering::Relaxed);
+    assert!(count > 10);
+    assert!(count < 100_000);
+}
@@ -0,0 +1,38 @@
+//! par_map! results, with and without the fallible clause.
+
+use rustmp::par_map;
+
+#[test]
+fn values_in_iteration_order() {
+    let squares = par_map! {
+        for i in 0..1000, blocksize 7, {
+            i * i
+        }
+    };
+    assert_eq!(squares, (0..1000).map(|i| i * i).collect::<Vec<_>>());
+}
+
+#[test]
+fn fallible_map_returns_every_value() {
+    let offset = 1;
+    let values: Result<Vec<usize>, String> = par_map! {
+        for i in 0..100, shared offset, fallible into res: String, blocksize 4, {
+            Ok(i + offset)
+        }
+    };
+    assert_eq!(values, Ok((1..101).collect()));
+}
+
+#[test]
+fn fallible_map_returns_the_error() {
+    let values = par_map! {
+        for i in 0..100, fallible into res: String, {
+            if i == 42 {
+                Err(format!("failed at {}", i))
+            } else {
+                Ok(i)
+            }
+        }
+    };
+    assert_eq!(values, Err("failed at 42".to_string()));
+}
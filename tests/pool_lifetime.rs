@@ -0,0 +1,72 @@
+//! Pools dropped or shut down while their teams or regions are still in use.
+
+use rustmp::{as_static_job, par_for, Job, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+fn counting_tasks(num_tasks: usize, count: &Arc<AtomicUsize>) -> Vec<Job> {
+    (0..num_tasks)
+        .map(|_| {
+            let count = count.clone();
+            as_static_job(move || {
+                sleep(Duration::from_millis(10));
+                count.fetch_add(1, Ordering::Relaxed);
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn team_outlives_its_pool() {
+    let team = ThreadPool::new(4).team();
+    let count = Arc::new(AtomicUsize::new(0));
+    team.exec(counting_tasks(4, &count));
+    assert_eq!(count.load(Ordering::Relaxed), 4);
+}
+
+#[test]
+fn pool_clause_with_a_temporary_pool() {
+    let count = AtomicUsize::new(0);
+    par_for! {
+        for _i in 0..10, pool rustmp::ThreadPool::new(2), {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    assert_eq!(count.load(Ordering::Relaxed), 10);
+}
+
+#[test]
+fn region_outlives_its_pool() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let handle = ThreadPool::new(3).spawn_region(counting_tasks(3, &count));
+    handle.join();
+    assert_eq!(count.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn shutdown_with_a_live_team() {
+    let pool = ThreadPool::new(2);
+    let team = pool.team();
+    pool.shutdown();
+    let count = Arc::new(AtomicUsize::new(0));
+    team.exec(counting_tasks(2, &count));
+    assert_eq!(count.load(Ordering::Relaxed), 2);
+    drop(team);
+    assert_eq!(pool.team().num_threads, 2);
+}
+
+#[test]
+fn both_paths_restart_a_shut_down_pool() {
+    let pool = ThreadPool::new(2);
+    let count = Arc::new(AtomicUsize::new(0));
+    pool.shutdown();
+    pool.team().exec(counting_tasks(2, &count));
+    pool.shutdown();
+    pool.manager()
+        .lock()
+        .unwrap()
+        .exec(counting_tasks(2, &count));
+    assert_eq!(count.load(Ordering::Relaxed), 4);
+}
@@ -0,0 +1,61 @@
+//! RmpIterator results under dynamic block hand-out, on the global pool and on other pools.
+
+use rustmp::{get_num_threads, RmpIterator, ThreadPool};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Makes blocks finish out of order: early items take the longest.
+fn uneven_work(i: usize) {
+    if i.is_multiple_of(97) {
+        sleep(Duration::from_micros(200 * (10 - i / 1000) as u64));
+    }
+}
+
+#[test]
+fn collect_keeps_iteration_order() {
+    let squares: Vec<usize> = (0..10_000)
+        .rmp_map(|i| {
+            uneven_work(i);
+            i * i
+        })
+        .collect();
+    assert_eq!(squares, (0..10_000).map(|i| i * i).collect::<Vec<_>>());
+}
+
+#[test]
+fn collect_without_size_hint_keeps_iteration_order() {
+    // A filter has no lower size bound, so every block holds a single item
+    let evens: Vec<usize> = (0..2_000)
+        .filter(|i| i % 2 == 0)
+        .rmp_map(|i| {
+            uneven_work(i);
+            i
+        })
+        .collect();
+    assert_eq!(evens, (0..2_000).step_by(2).collect::<Vec<_>>());
+}
+
+#[test]
+fn reduce_keeps_iteration_order() {
+    // Concatenation is associative but not commutative
+    let digits = (0..1_000)
+        .rmp_map(|i| {
+            uneven_work(i);
+            (i % 10).to_string()
+        })
+        .rmp_reduce(String::new, |a, b| a + &b);
+    let expected = (0..1_000).map(|i| (i % 10).to_string()).collect::<String>();
+    assert_eq!(digits, expected);
+}
+
+#[test]
+fn rmp_on_runs_on_the_given_pool() {
+    let pool = ThreadPool::new(3);
+    let threads = (0..100)
+        .rmp_on(&pool)
+        .rmp_map(|_| get_num_threads())
+        .rmp_reduce(|| 0, usize::max);
+    assert_eq!(threads, 3);
+    let sum = (1..=100).rmp_on(&pool).rmp_reduce(|| 0, |a, b| a + b);
+    assert_eq!(sum, 5050);
+}
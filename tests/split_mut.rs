@@ -0,0 +1,32 @@
+//! par_for! loops writing through the split_mut clause.
+
+use rustmp::par_for;
+
+#[test]
+fn rows_written_by_their_iteration() {
+    let n = 64;
+    let mut result = vec![vec![0; n]; n];
+    par_for! {
+        for i in 0..n, split_mut result, blocksize 3, {
+            for (j, value) in result.iter_mut().enumerate() {
+                *value = i * n + j;
+            }
+        }
+    }
+    for (i, row) in result.iter().enumerate() {
+        assert!(row.iter().enumerate().all(|(j, &value)| value == i * n + j));
+    }
+}
+
+#[test]
+fn chunks_of_a_flat_vec() {
+    let mut flat = vec![0; 1000];
+    let mut chunks = flat.chunks_mut(10).collect::<Vec<_>>();
+    par_for! {
+        for i in 0..100, split_mut chunks, {
+            chunks.fill(i);
+        }
+    }
+    drop(chunks);
+    assert!(flat.iter().enumerate().all(|(k, &value)| value == k / 10));
+}
@@ -0,0 +1,12 @@
+use rustmp::parallel;
+
+fn main() {
+    let a = vec![0; 4];
+    let b = vec![0; 4];
+    parallel! {
+        #[parallel_for(default(none), shared(a))]
+        for i in 0..4 {
+            let _ = a[i] + b[i];
+        }
+    }
+}
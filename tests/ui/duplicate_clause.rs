@@ -0,0 +1,10 @@
+use rustmp::parallel;
+
+fn main() {
+    parallel! {
+        #[parallel_for(blocksize(2), blocksize(4))]
+        for i in 0..4 {
+            let _ = i;
+        }
+    }
+}
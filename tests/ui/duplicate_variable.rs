@@ -0,0 +1,11 @@
+use rustmp::parallel;
+
+fn main() {
+    let a = vec![0; 4];
+    parallel! {
+        #[parallel_for(shared(a), private(a))]
+        for i in 0..4 {
+            let _ = a[i];
+        }
+    }
+}
@@ -0,0 +1,11 @@
+use rustmp::par_map;
+
+fn main() {
+    let mut count = 0;
+    let _values = par_map! {
+        for i in 0..4, shared_mut count, {
+            *count.write() += 1;
+            i
+        }
+    };
+}
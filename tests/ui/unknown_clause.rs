@@ -0,0 +1,10 @@
+use rustmp::parallel;
+
+fn main() {
+    parallel! {
+        #[parallel_for(firstprivate(x))]
+        for i in 0..4 {
+            let _ = i;
+        }
+    }
+}